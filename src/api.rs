@@ -1,16 +1,75 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
-use reqwest::header::{AUTHORIZATION, HeaderMap};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use reqwest::header::{AUTHORIZATION, HeaderMap, USER_AGENT};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use thiserror::Error;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{APP_NAME, VERSION};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::plan::PlanAction;
 
 pub struct CfApi {
     client: reqwest::Client,
+    retry_budget: RetryBudget,
+    metrics: Arc<Metrics>,
+    creating: Mutex<HashMap<CreateKey, Arc<tokio::sync::Mutex<bool>>>>,
+    endpoint: String,
+}
+
+type CreateKey = (String, String, RecordType, String);
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_TOKENS: f64 = 10.0;
+
+// Every request earns `ratio` of a retry and every retry spends a whole one, so during a sustained outage
+// retries stay a fraction of the traffic instead of multiplying it.
+struct RetryBudget {
+    ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            tokens: Mutex::new(MAX_RETRY_TOKENS),
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(MAX_RETRY_TOKENS);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
 }
 
 pub const CF_ENDPOINT: &str = "https://api.cloudflare.com/client/v4";
+const RECORDS_PER_PAGE: u32 = 100;
+// Bounds listing when the API keeps reporting more pages than it should.
+const MAX_PAGES: u32 = 1000;
+const CUSTOM_HOSTNAMES_PER_PAGE: u32 = 50;
+const ZONES_PER_PAGE: u32 = 50;
 
 #[derive(Deserialize, Debug)]
 pub struct Zone {
@@ -18,80 +77,384 @@ pub struct Zone {
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+/// A Cloudflare for SaaS custom hostname, a customer domain served through our zone.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CustomHostname {
+    pub id: String,
+    pub hostname: String,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(from = "String", into = "String")]
+pub enum RecordType {
+    #[default]
+    A,
+    AAAA,
+    CNAME,
+    TXT,
+    MX,
+    SRV,
+    CAA,
+    NS,
+    PTR,
+    Other(String),
+}
+
+impl RecordType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RecordType::A => "A",
+            RecordType::AAAA => "AAAA",
+            RecordType::CNAME => "CNAME",
+            RecordType::TXT => "TXT",
+            RecordType::MX => "MX",
+            RecordType::SRV => "SRV",
+            RecordType::CAA => "CAA",
+            RecordType::NS => "NS",
+            RecordType::PTR => "PTR",
+            RecordType::Other(other) => other,
+        }
+    }
+
+    pub fn is_proxiable(&self) -> bool {
+        matches!(self, RecordType::A | RecordType::AAAA | RecordType::CNAME)
+    }
+}
+
+impl From<String> for RecordType {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "A" => RecordType::A,
+            "AAAA" => RecordType::AAAA,
+            "CNAME" => RecordType::CNAME,
+            "TXT" => RecordType::TXT,
+            "MX" => RecordType::MX,
+            "SRV" => RecordType::SRV,
+            "CAA" => RecordType::CAA,
+            "NS" => RecordType::NS,
+            "PTR" => RecordType::PTR,
+            _ => RecordType::Other(value),
+        }
+    }
+}
+
+impl From<RecordType> for String {
+    fn from(value: RecordType) -> Self {
+        value.as_str().into()
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct Record {
     pub id: String,
     #[serde(rename = "type")]
-    pub _type: String,
+    pub _type: RecordType,
     pub name: String,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxied: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<Value>,
+    #[serde(default, skip_serializing)]
+    pub proxiable: Option<bool>,
+    #[serde(default, skip_serializing)]
+    pub created_on: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub locked: Option<bool>,
+    /// Zone pinned by the zone annotation, otherwise the record belongs to the most specific zone containing it.
+    #[serde(skip)]
+    pub zone: Option<String>,
+}
+
+impl Hash for Record {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self._type.hash(state);
+        self.name.hash(state);
+        self.content.hash(state);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiError {
+    pub code: u32,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResultInfo {
+    page: u32,
+    total_pages: u32,
 }
 
 #[derive(Deserialize, Debug)]
 struct CfResponse<T> {
     success: bool,
     result: Option<T>,
-    errors: Value,
+    errors: Vec<ApiError>,
+    result_info: Option<ResultInfo>,
 }
 
 impl<T> CfResponse<T> {
     fn result(self) -> Result<T> {
         if !self.success {
-            Err(CfError::Api(serde_json::to_string(&self.errors).unwrap()))
+            Err(CfError::Api(self.errors))
         } else {
             Ok(self.result.unwrap())
         }
     }
 }
 
+const PERMISSION_ERROR_CODES: [u32; 2] = [9109, 10000];
+const ALREADY_EXISTS_CODES: [u32; 2] = [81057, 81058];
+
 #[derive(Error, Debug)]
 pub enum CfError {
-    #[error("cf api error: {0}")]
-    Api(String),
+    #[error("cf api error: {}", serde_json::to_string(.0).unwrap())]
+    Api(Vec<ApiError>),
     #[error("cf transport error: {0}")]
     Transport(#[from] reqwest::Error),
+    #[error("cf api conflict")]
+    Conflict,
+    #[error("cf api returned more than {0} pages")]
+    TooManyPages(u32),
+}
+
+impl CfError {
+    pub fn is_permission_error(&self) -> bool {
+        matches!(self, CfError::Api(errors) if errors.iter().any(|e| PERMISSION_ERROR_CODES.contains(&e.code)))
+    }
+
+    pub fn is_already_exists(&self) -> bool {
+        matches!(self, CfError::Api(errors) if errors.iter().any(|e| ALREADY_EXISTS_CODES.contains(&e.code)))
+    }
 }
 
 type Result<T> = std::result::Result<T, CfError>;
 
+#[derive(Serialize)]
+struct BatchDelete<'a> {
+    id: &'a str,
+}
+
+#[derive(Serialize, Default)]
+struct BatchRequest<'a> {
+    deletes: Vec<BatchDelete<'a>>,
+    puts: Vec<&'a Record>,
+    posts: Vec<&'a Record>,
+}
+
+impl<'a> BatchRequest<'a> {
+    fn new(plan: &'a [PlanAction]) -> Self {
+        let mut request = Self::default();
+        for change in plan {
+            match change {
+                PlanAction::Add(record) => request.posts.push(record),
+                PlanAction::Delete(record) => request.deletes.push(BatchDelete { id: &record.id }),
+                PlanAction::Update(record) => request.puts.push(record),
+            }
+        }
+        request
+    }
+}
+
+fn user_agent(config: &Config) -> String {
+    match &config.cluster_name {
+        Some(cluster) => format!("{}/{} (cluster={})", APP_NAME, VERSION, cluster),
+        None => format!("{}/{}", APP_NAME, VERSION)
+    }
+}
+
+// Other client errors, like a missing permission, fail the same way when retried.
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
 impl CfApi {
-    pub fn new(token: &str) -> Self {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self::with_endpoint(config, metrics, CF_ENDPOINT)
+    }
+
+    pub fn with_endpoint(config: &Config, metrics: Arc<Metrics>, endpoint: &str) -> Self {
         let mut headers = HeaderMap::new();
-        headers.insert(AUTHORIZATION, format!("Bearer {}", token).try_into().unwrap());
+        headers.insert(AUTHORIZATION, format!("Bearer {}", config.cf_token).try_into().unwrap());
+        headers.insert(USER_AGENT, user_agent(config).try_into().expect("CLUSTER_NAME is checked by Config::problems"));
 
+        // A reconcile issues its record changes back to back, so a few idle connections per host let a
+        // burst reuse them instead of handshaking for every call. The 90s idle timeout outlives the 60s
+        // reconcile interval so connections carry over between cycles, and TCP keep-alive notices
+        // connections silently dropped by middleboxes in the meantime.
         let client = Client::builder()
             .default_headers(headers)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(Duration::from_secs(30))
             .build()
             .unwrap();
         Self {
-            client
+            client,
+            retry_budget: RetryBudget::new(config.retry_budget),
+            metrics,
+            creating: Default::default(),
+            endpoint: endpoint.into(),
         }
     }
 
+    // Transport errors, rate limiting and server errors are retried with backoff while the budget lasts. A POST
+    // may have been applied when the connection broke afterwards, so it's only retried when it never connected.
+    // Our PATCHes set fields and are as safe to repeat as PUTs and DELETEs.
+    async fn send(&self, operation: &str, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build()?;
+        let idempotent = request.method() != Method::POST;
+        let mut attempt = 1;
+        loop {
+            self.retry_budget.deposit();
+            let retry = request.try_clone();
+            let started = Instant::now();
+            let result = self.client.execute(request).await;
+            let elapsed = started.elapsed();
+            self.metrics.api_duration.with_label_values(&[operation]).observe(elapsed.as_secs_f64());
+            match &result {
+                Ok(resp) => debug!("Cloudflare {} returned {} in {:?}", operation, resp.status(), elapsed),
+                Err(err) => debug!("Cloudflare {} failed in {:?}: {}", operation, elapsed, err)
+            }
+            let retryable = match &result {
+                Ok(resp) => retryable(resp.status()),
+                Err(err) => idempotent || err.is_connect()
+            };
+            request = match retry {
+                Some(retry) if retryable && attempt < MAX_ATTEMPTS => retry,
+                _ => return Ok(result?)
+            };
+            if !self.retry_budget.withdraw() {
+                warn!("Cloudflare retry budget exhausted, not retrying");
+                return Ok(result?);
+            }
+
+            sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+            attempt += 1;
+        }
+    }
+
+    // Lists every page of `url`, up to `MAX_PAGES`.
+    async fn pages<T: DeserializeOwned>(&self, operation: &str, url: &str, per_page: u32) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        for page in 1..=MAX_PAGES {
+            let resp: CfResponse<Vec<T>> = self.send(operation, self.client.get(url)
+                .query(&[("page", page), ("per_page", per_page)]))
+                .await?
+                .json()
+                .await?;
+            let last = match &resp.result_info {
+                Some(info) => info.page >= info.total_pages,
+                None => true
+            };
+            items.extend(resp.result()?);
+            if last {
+                return Ok(items);
+            }
+        }
+        Err(CfError::TooManyPages(MAX_PAGES))
+    }
+
     pub async fn zones(&self) -> Result<Vec<Zone>> {
-        let resp: CfResponse<Vec<Zone>> = self.client.get(format!("{}/zones", CF_ENDPOINT))
-            .send()
+        self.pages("zones", &format!("{}/zones", self.endpoint), ZONES_PER_PAGE).await
+    }
+
+    /// The zone with the name, looked up by name so it's found however many zones the token can see.
+    pub async fn zone(&self, name: &str) -> Result<Option<Zone>> {
+        let url = format!("{}/zones", self.endpoint);
+        let resp: CfResponse<Vec<Zone>> = self.send("zones", self.client.get(url).query(&[("name", name)]))
             .await?
             .json()
             .await?;
-        resp.result()
+        Ok(resp.result()?.into_iter().find(|zone| zone.name == name))
     }
 
     pub async fn records(&self, zone_id: &str) -> Result<Vec<Record>> {
-        let resp: CfResponse<Vec<Record>> = self.client.get(format!("{}/zones/{}/dns_records",
-                                                                    CF_ENDPOINT, zone_id))
-            .send()
+        let url = format!("{}/zones/{}/dns_records", self.endpoint, zone_id);
+        let mut records: Vec<Record> = self.pages("list_records", &url, RECORDS_PER_PAGE).await?;
+        // Cloudflare's order varies between listings, sorted records keep logs comparable across reconciles.
+        fn key(record: &Record) -> (&str, &str, &str) {
+            (&record.name, record._type.as_str(), &record.content)
+        }
+        records.sort_by(|a, b| key(a).cmp(&key(b)));
+        Ok(records)
+    }
+
+    /// The records named `name`, of any type without `_type`.
+    pub async fn list_records_by_name(&self, zone_id: &str, name: &str, _type: Option<&RecordType>)
+                                      -> Result<Vec<Record>> {
+        let url = format!("{}/zones/{}/dns_records", self.endpoint, zone_id);
+        let mut query = vec![("name", name)];
+        query.extend(_type.map(|_type| ("type", _type.as_str())));
+        let resp: CfResponse<Vec<Record>> = self.send("list_records", self.client.get(url).query(&query))
             .await?
             .json()
             .await?;
         resp.result()
     }
 
+    // Creates of the same record overlapping each other are coalesced into one request, later ones wait for it
+    // and succeed with it.
     pub async fn create_record(&self, zone_id: &str, record: &Record) -> Result<()> {
-        let resp: CfResponse<Value> = self.client.post(format!("{}/zones/{}/dns_records",
-                                                               CF_ENDPOINT, zone_id))
-            .json(&record)
-            .send()
+        let key = (zone_id.to_string(), record.name.clone(), record._type.clone(), record.content.clone());
+        let slot = self.creating.lock().unwrap().entry(key.clone()).or_default().clone();
+        let result = {
+            let mut created = slot.lock().await;
+            if *created {
+                Ok(())
+            } else {
+                let result = self.post_record(zone_id, record).await;
+                *created = result.is_ok();
+                result
+            }
+        };
+
+        let mut creating = self.creating.lock().unwrap();
+        if Arc::strong_count(&slot) == 2 {
+            creating.remove(&key);
+        }
+        result
+    }
+
+    // A create failing because the record exists means our listing was stale, the existing record is updated
+    // instead.
+    pub async fn create_or_update(&self, zone_id: &str, record: &Record) -> Result<()> {
+        let err = match self.create_record(zone_id, record).await {
+            Err(err) if err.is_already_exists() => err,
+            result => return result
+        };
+
+        let existing = self.list_records_by_name(zone_id, &record.name, Some(&record._type)).await?;
+        let existing = match existing.iter().find(|r| r.content == record.content) {
+            Some(existing) => existing,
+            None if existing.len() == 1 => &existing[0],
+            None => return Err(err)
+        };
+        warn!("Record {} {} already exists, updating it instead", &record._type, &record.name);
+        self.update_record(zone_id, &Record {
+            id: existing.id.clone(),
+            ..record.clone()
+        }).await
+    }
+
+    async fn post_record(&self, zone_id: &str, record: &Record) -> Result<()> {
+        let url = format!("{}/zones/{}/dns_records", self.endpoint, zone_id);
+        let resp: CfResponse<Value> = self.send("create_record", self.client.post(url).json(&record))
             .await?
             .json()
             .await?;
@@ -100,9 +463,26 @@ impl CfApi {
     }
 
     pub async fn delete_record(&self, zone_id: &str, record_id: &str) -> Result<()> {
-        let resp: CfResponse<Value> = self.client.delete(format!("{}/zones/{}/dns_records/{}",
-                                                                 CF_ENDPOINT, zone_id, record_id))
-            .send()
+        let url = format!("{}/zones/{}/dns_records/{}", self.endpoint, zone_id, record_id);
+        let resp: CfResponse<Value> = self.send("delete_record", self.client.delete(url))
+            .await?
+            .json()
+            .await?;
+        resp.result()?;
+        Ok(())
+    }
+
+    pub async fn list_custom_hostnames(&self, zone_id: &str) -> Result<Vec<CustomHostname>> {
+        let url = format!("{}/zones/{}/custom_hostnames", self.endpoint, zone_id);
+        self.pages("list_custom_hostnames", &url, CUSTOM_HOSTNAMES_PER_PAGE).await
+    }
+
+    // Certificates of custom hostnames are validated over HTTP, which works once the customer points the hostname
+    // at us.
+    pub async fn create_custom_hostname(&self, zone_id: &str, hostname: &str) -> Result<()> {
+        let url = format!("{}/zones/{}/custom_hostnames", self.endpoint, zone_id);
+        let body = json!({"hostname": hostname, "ssl": {"method": "http", "type": "dv"}});
+        let resp: CfResponse<Value> = self.send("create_custom_hostname", self.client.post(url).json(&body))
             .await?
             .json()
             .await?;
@@ -110,15 +490,442 @@ impl CfApi {
         Ok(())
     }
 
+    pub async fn get_record(&self, zone_id: &str, record_id: &str) -> Result<Record> {
+        let url = format!("{}/zones/{}/dns_records/{}", self.endpoint, zone_id, record_id);
+        let resp: CfResponse<Record> = self.send("get_record", self.client.get(url))
+            .await?
+            .json()
+            .await?;
+        resp.result()
+    }
+
+    async fn put_record(&self, zone_id: &str, record: &Record) -> Result<()> {
+        let url = format!("{}/zones/{}/dns_records/{}", self.endpoint, zone_id, &record.id);
+        let resp = self.send("update_record", self.client.put(url).json(record))
+            .await?;
+        if resp.status() == StatusCode::CONFLICT {
+            return Err(CfError::Conflict);
+        }
+        let resp: CfResponse<Value> = resp.json().await?;
+        resp.result()?;
+        Ok(())
+    }
+
+    // A conflict means the record changed under us, the update is retried once against its current state. Fields
+    // the update leaves unset keep their current values, so it doesn't revert what changed meanwhile.
     pub async fn update_record(&self, zone_id: &str, record: &Record) -> Result<()> {
-        let resp: CfResponse<Value> = self.client.put(format!("{}/zones/{}/dns_records/{}",
-                                                              CF_ENDPOINT, zone_id, &record.id))
-            .json(record)
-            .send()
+        match self.put_record(zone_id, record).await {
+            Err(CfError::Conflict) => {
+                let current = self.get_record(zone_id, &record.id).await?;
+                self.put_record(zone_id, &Record {
+                    id: current.id,
+                    data: record.data.clone().or(current.data),
+                    comment: record.comment.clone().or(current.comment),
+                    ttl: record.ttl.or(current.ttl),
+                    proxied: record.proxied.or(current.proxied),
+                    settings: record.settings.clone().or(current.settings),
+                    ..record.clone()
+                }).await
+            }
+            result => result
+        }
+    }
+
+    pub async fn patch_record(&self, zone_id: &str, record_id: &str, partial: &Value) -> Result<()> {
+        let url = format!("{}/zones/{}/dns_records/{}", self.endpoint, zone_id, record_id);
+        let resp: CfResponse<Value> = self.send("patch_record", self.client.patch(url).json(partial))
             .await?
             .json()
             .await?;
         resp.result()?;
         Ok(())
     }
+
+    pub async fn apply_batch<'a>(&self, zone_id: &str, plan: &'a [PlanAction], batch_size: usize)
+                                 -> Vec<(&'a [PlanAction], Result<()>)> {
+        let mut results = Vec::new();
+        let mut rest = plan;
+        while let Some(first) = rest.first() {
+            // Cloudflare runs a batch's deletes before its puts and posts, so every chunk holds one kind of action
+            // to keep the order the plan was sorted in.
+            let kind = mem::discriminant(first);
+            let len = rest.iter().take(batch_size).take_while(|change| mem::discriminant(*change) == kind).count();
+            let (chunk, remaining) = rest.split_at(len);
+            results.push((chunk, self.batch(zone_id, chunk).await));
+            rest = remaining;
+        }
+        results
+    }
+
+    async fn batch(&self, zone_id: &str, plan: &[PlanAction]) -> Result<()> {
+        let url = format!("{}/zones/{}/dns_records/batch", self.endpoint, zone_id);
+        let resp: CfResponse<Value> = self.send("batch", self.client.post(url).json(&BatchRequest::new(plan)))
+            .await?
+            .json()
+            .await?;
+        resp.result()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::{Body, Request, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Requests = Arc<Mutex<Vec<(Method, String, Value)>>>;
+
+    // A Cloudflare API answering every request with `respond`, recording the method, path with query and body.
+    fn mock<F>(respond: F) -> (CfApi, Requests)
+        where F: Fn(&Method, &str, &Value) -> (u16, Value) + Send + Sync + 'static {
+        let requests = Requests::default();
+        let respond = Arc::new(respond);
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let (respond, recorded) = (respond.clone(), recorded.clone());
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let (respond, recorded) = (respond.clone(), recorded.clone());
+                    async move {
+                        let method = req.method().clone();
+                        let path = req.uri().path_and_query().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                        let (status, response) = respond(&method, &path, &body);
+                        recorded.lock().unwrap().push((method, path, body));
+                        Ok::<_, hyper::Error>(hyper::Response::builder()
+                            .status(status)
+                            .body(Body::from(response.to_string()))
+                            .unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::task::spawn(server);
+        (client(&endpoint), requests)
+    }
+
+    fn client(endpoint: &str) -> CfApi {
+        CfApi::with_endpoint(&Config::for_test(&[]), Arc::new(Metrics::new()), endpoint)
+    }
+
+    fn ok(result: Value) -> (u16, Value) {
+        (200, json!({"success": true, "errors": [], "result": result}))
+    }
+
+    // A server closing every connection right after accepting it, counting the connections.
+    async fn dropping() -> (CfApi, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        (client(&endpoint), accepted)
+    }
+
+    fn a_record() -> Record {
+        Record {
+            _type: RecordType::A,
+            name: "app.example.com".into(),
+            content: "192.0.2.1".into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn post_is_not_retried_after_transport_error() {
+        let (api, accepted) = dropping().await;
+        assert!(api.create_record("zone", &a_record()).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn put_is_retried_after_transport_error() {
+        let (api, accepted) = dropping().await;
+        assert!(api.update_record("zone", &Record { id: "1".into(), ..a_record() }).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn large_batches_are_split() {
+        let (api, requests) = mock(|_, _, _| ok(json!({})));
+        let plan: Vec<PlanAction> = (0..500)
+            .map(|i| PlanAction::Add(Record { content: format!("192.0.{}.{}", i / 256, i % 256), ..a_record() }))
+            .collect();
+        let results = api.apply_batch("zone", &plan, 200).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        let requests = requests.lock().unwrap();
+        let posts: Vec<usize> = requests.iter()
+            .map(|(method, path, body)| {
+                assert_eq!((method, path.as_str()), (&Method::POST, "/zones/zone/dns_records/batch"));
+                body["posts"].as_array().unwrap().len()
+            })
+            .collect();
+        assert_eq!(posts, vec![200, 200, 100]);
+    }
+
+    #[tokio::test]
+    async fn user_agent_names_the_cluster() {
+        let agents = Arc::new(Mutex::new(Vec::new()));
+        let recorded = agents.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    recorded.lock().unwrap().push(req.headers()[USER_AGENT].to_str().unwrap().to_string());
+                    async { Ok::<_, hyper::Error>(hyper::Response::new(Body::from(ok(json!([])).1.to_string()))) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::task::spawn(server);
+
+        let config = Config::for_test(&[("CLUSTER_NAME", "prod-eu")]);
+        let api = CfApi::with_endpoint(&config, Arc::new(Metrics::new()), &endpoint);
+        api.zones().await.unwrap();
+        assert_eq!(*agents.lock().unwrap(), vec![format!("{}/{} (cluster=prod-eu)", APP_NAME, VERSION)]);
+    }
+
+    #[tokio::test]
+    async fn batches_keep_the_plan_order() {
+        let marker = |name: &str, id: &str| Record {
+            id: id.into(),
+            _type: RecordType::TXT,
+            name: name.into(),
+            content: APP_NAME.into(),
+            ..Default::default()
+        };
+        let actual = vec![
+            Record { id: "1".into(), proxied: Some(true), ..a_record() },
+            marker("app.example.com", "2"),
+            Record { id: "3".into(), name: "old.example.com".into(), ..a_record() },
+            marker("old.example.com", "4"),
+        ];
+        let expected = vec![
+            Record { proxied: Some(false), ..a_record() },
+            Record { name: "new.example.com".into(), ..a_record() },
+        ];
+        let plan = crate::plan::plan(&expected, &actual, &Config::for_test(&[]));
+
+        let (api, requests) = mock(|_, _, _| ok(json!({})));
+        let results = api.apply_batch("zone", &plan, 200).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        let kinds: Vec<String> = requests.lock().unwrap().iter()
+            .map(|(_, _, body)| ["deletes", "puts", "posts"].iter()
+                .filter_map(|kind| match body[*kind].as_array().unwrap().len() {
+                    0 => None,
+                    len => Some(format!("{}={}", kind, len))
+                })
+                .collect::<Vec<_>>()
+                .join(","))
+            .collect();
+        assert_eq!(kinds, vec!["posts=1", "puts=1", "deletes=2"]);
+    }
+
+    #[tokio::test]
+    async fn listing_follows_pages() {
+        for (total, pages) in [(100, 1), (101, 2), (250, 3)] {
+            let (api, requests) = mock(move |_, path, _| {
+                let query = &path[path.find('?').unwrap() + 1..];
+                let page: usize = query.split('&')
+                    .find_map(|pair| pair.strip_prefix("page="))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let records: Vec<Value> = (0..total).skip((page - 1) * 100).take(100)
+                    .map(|i| json!({"id": i.to_string(), "type": "A", "name": format!("r{:03}.example.com", i),
+                                    "content": "192.0.2.1"}))
+                    .collect();
+                let (status, mut response) = ok(json!(records));
+                response["result_info"] = json!({"page": page, "total_pages": pages});
+                (status, response)
+            });
+            assert_eq!(api.records("zone").await.unwrap().len(), total);
+            let paths: Vec<String> = requests.lock().unwrap().iter().map(|(_, path, _)| path.clone()).collect();
+            let expected: Vec<String> = (1..=pages)
+                .map(|page| format!("/zones/zone/dns_records?page={}&per_page=100", page))
+                .collect();
+            assert_eq!(paths, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_records_are_sorted() {
+        let (api, _) = mock(|_, _, _| ok(json!([
+            {"id": "1", "type": "TXT", "name": "b.example.com", "content": "x"},
+            {"id": "2", "type": "A", "name": "b.example.com", "content": "192.0.2.2"},
+            {"id": "3", "type": "A", "name": "b.example.com", "content": "192.0.2.1"},
+            {"id": "4", "type": "AAAA", "name": "a.example.com", "content": "2001:db8::1"},
+        ])));
+        let ids: Vec<String> = api.records("zone").await.unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["4", "3", "2", "1"]);
+    }
+
+    #[tokio::test]
+    async fn patch_sends_only_the_changed_fields() {
+        let (api, requests) = mock(|_, _, _| ok(json!({})));
+        api.patch_record("zone", "1", &json!({"proxied": true})).await.unwrap();
+        assert_eq!(*requests.lock().unwrap(),
+                   vec![(Method::PATCH, "/zones/zone/dns_records/1".to_string(), json!({"proxied": true}))]);
+    }
+
+    #[tokio::test]
+    async fn conflicting_update_is_retried() {
+        let puts = AtomicUsize::new(0);
+        let (api, requests) = mock(move |method, _, _| match *method {
+            Method::GET => ok(json!({"id": "1", "type": "A", "name": "app.example.com", "content": "192.0.2.9",
+                                     "comment": "by hand", "settings": {"ipv4_only": true}})),
+            _ if puts.fetch_add(1, Ordering::SeqCst) == 0 => (409, json!({})),
+            _ => ok(json!({}))
+        });
+        api.update_record("zone", &Record { id: "1".into(), ..a_record() }).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<(&Method, &str)> = requests.iter().map(|(method, path, _)| (method, path.as_str())).collect();
+        assert_eq!(calls, vec![
+            (&Method::PUT, "/zones/zone/dns_records/1"),
+            (&Method::GET, "/zones/zone/dns_records/1"),
+            (&Method::PUT, "/zones/zone/dns_records/1"),
+        ]);
+        // The retry keeps our content and the fields changed meanwhile that we don't set.
+        assert_eq!(requests[0].2, json!({"id": "1", "type": "A", "name": "app.example.com", "content": "192.0.2.1"}));
+        assert_eq!(requests[2].2, json!({"id": "1", "type": "A", "name": "app.example.com", "content": "192.0.2.1",
+                                         "comment": "by hand", "settings": {"ipv4_only": true}}));
+    }
+
+    #[tokio::test]
+    async fn existing_record_is_updated_instead() {
+        let (api, requests) = mock(|method, _, _| match *method {
+            Method::POST => (400, json!({"success": false, "errors": [{"code": 81058, "message": "exists"}]})),
+            Method::GET => ok(json!([{"id": "1", "type": "A", "name": "app.example.com", "content": "192.0.2.1"}])),
+            _ => ok(json!({}))
+        });
+        api.create_or_update("zone", &a_record()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<(&Method, &str)> = requests.iter().map(|(method, path, _)| (method, path.as_str())).collect();
+        assert_eq!(calls, vec![
+            (&Method::POST, "/zones/zone/dns_records"),
+            (&Method::GET, "/zones/zone/dns_records?name=app.example.com&type=A"),
+            (&Method::PUT, "/zones/zone/dns_records/1"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn overlapping_creates_send_one_request() {
+        let (api, requests) = mock(|_, _, _| ok(json!({})));
+        let record = a_record();
+        let (first, second) = futures::join!(api.create_record("zone", &record), api.create_record("zone", &record));
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(api.creating.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn custom_hostnames_are_listed_and_created() {
+        let (api, requests) = mock(|method, path, _| match *method {
+            Method::GET => {
+                let page = if path.contains("page=1&") { 1 } else { 2 };
+                let (status, mut response) = ok(json!([{"id": format!("h{}", page),
+                                                        "hostname": format!("shop{}.customer.com", page)}]));
+                response["result_info"] = json!({"page": page, "total_pages": 2});
+                (status, response)
+            }
+            _ => ok(json!({"id": "h3", "hostname": "new.customer.com"}))
+        });
+        let hostnames: Vec<String> = api.list_custom_hostnames("zone").await.unwrap().into_iter()
+            .map(|custom| custom.hostname)
+            .collect();
+        assert_eq!(hostnames, vec!["shop1.customer.com", "shop2.customer.com"]);
+        api.create_custom_hostname("zone", "new.customer.com").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.iter().map(|(_, path, _)| path.as_str()).collect::<Vec<_>>(), vec![
+            "/zones/zone/custom_hostnames?page=1&per_page=50",
+            "/zones/zone/custom_hostnames?page=2&per_page=50",
+            "/zones/zone/custom_hostnames",
+        ]);
+        assert_eq!(requests[2].0, Method::POST);
+        assert_eq!(requests[2].2, json!({"hostname": "new.customer.com", "ssl": {"method": "http", "type": "dv"}}));
+    }
+
+    #[tokio::test]
+    async fn zones_are_looked_up_by_name() {
+        let (api, requests) = mock(|_, path, _| match path {
+            "/zones?name=example.com" => ok(json!([{"id": "1", "name": "example.com"}])),
+            _ => ok(json!([]))
+        });
+        assert_eq!(api.zone("example.com").await.unwrap().unwrap().id, "1");
+        assert!(api.zone("other.com").await.unwrap().is_none());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn zone_listing_follows_pages() {
+        let (api, requests) = mock(|_, path, _| {
+            let page = if path.contains("page=1&") { 1 } else { 2 };
+            let (status, mut response) = ok(json!([{"id": page.to_string(), "name": format!("zone{}.com", page)}]));
+            response["result_info"] = json!({"page": page, "total_pages": 2});
+            (status, response)
+        });
+        let zones: Vec<String> = api.zones().await.unwrap().into_iter().map(|zone| zone.name).collect();
+        assert_eq!(zones, vec!["zone1.com", "zone2.com"]);
+        assert_eq!(requests.lock().unwrap()[1].1, "/zones?page=2&per_page=50");
+    }
+
+    #[tokio::test]
+    async fn endless_custom_hostname_pages_fail() {
+        let (api, requests) = mock(|_, _, _| {
+            let (status, mut response) = ok(json!([]));
+            response["result_info"] = json!({"page": 1, "total_pages": 2});
+            (status, response)
+        });
+        assert!(matches!(api.list_custom_hostnames("zone").await, Err(CfError::TooManyPages(MAX_PAGES))));
+        assert_eq!(requests.lock().unwrap().len(), MAX_PAGES as usize);
+    }
+
+    #[test]
+    fn only_throttling_and_server_errors_are_retryable() {
+        for (status, expected) in [(429, true), (500, true), (502, true), (503, true), (400, false), (403, false),
+                                   (404, false)] {
+            assert_eq!(retryable(StatusCode::from_u16(status).unwrap()), expected, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_error_is_retried() {
+        let calls = AtomicUsize::new(0);
+        let (api, requests) = mock(move |_, _, _| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => (502, json!({})),
+            _ => ok(json!([{"id": "1", "name": "example.com"}]))
+        });
+        let zones = api.zones().await.unwrap();
+        assert_eq!(zones[0].name, "example.com");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_error_fails_fast() {
+        let (api, requests) = mock(|_, _, _| {
+            (403, json!({"success": false, "errors": [{"code": 10000, "message": "Authentication error"}]}))
+        });
+        assert!(api.zones().await.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
 }
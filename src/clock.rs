@@ -0,0 +1,43 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(any(test, feature = "test-clock"))]
+use std::sync::Mutex;
+#[cfg(any(test, feature = "test-clock"))]
+use std::time::Duration;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn timestamp(&self) -> u64 {
+        self.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests of time dependent behaviour.
+#[cfg(any(test, feature = "test-clock"))]
+pub struct TestClock(Mutex<SystemTime>);
+
+#[cfg(any(test, feature = "test-clock"))]
+impl TestClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "test-clock"))]
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,617 @@
+use std::cell::RefCell;
+use std::env;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+use regex::Regex;
+use reqwest::header::HeaderValue;
+
+use crate::APP_NAME;
+use crate::api::RecordType;
+use crate::plan::{valid_hostname, valid_record_name, Ownership};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub interval: Duration,
+    /// Whether the zone is one of `REVERSE_ZONES`, only receiving PTR records.
+    pub reverse: bool,
+}
+
+pub struct Config {
+    pub zones: Vec<ZoneConfig>,
+    pub cf_token: String,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    pub ownership: Ownership,
+    pub owner_id: Option<String>,
+    /// Whether markers without an owner, written before `OWNER_ID` was set, are taken over by this owner.
+    pub migrate_bare_markers: bool,
+    pub batch: bool,
+    pub batch_size: usize,
+    pub ingress_classes: Vec<String>,
+    pub max_records: Option<usize>,
+    pub require_ready: bool,
+    pub listen_addr: SocketAddr,
+    pub proxied_default: bool,
+    pub cluster_name: Option<String>,
+    pub list_zones: bool,
+    pub export_zone: bool,
+    pub log_format: LogFormat,
+    pub reconcile_timeout: Duration,
+    pub ready_max_failures: u32,
+    pub ready_max_age: Duration,
+    pub watch_resources: Vec<String>,
+    pub ingressroute_service: String,
+    pub manage_since: Option<DateTime<FixedOffset>>,
+    pub retry_budget: f64,
+    pub soft_delete: bool,
+    pub paused: bool,
+    pub pause_configmap: Option<(String, String)>,
+    pub auto_hostname_template: Option<String>,
+    pub txt_marker_prefix: Option<String>,
+    pub reverse_zones: Vec<String>,
+    pub txt_marker_fields: Vec<String>,
+    pub webhook: bool,
+    pub history_size: usize,
+    pub stamp_resource_version: bool,
+    /// Whether unmanaged records matching the expected ones are taken over, set by `ADOPT` or its alias
+    /// `RECLAIM_ORPHANS`.
+    pub adopt: bool,
+    pub max_deletes: Option<usize>,
+    pub publish_service: Option<String>,
+    pub include_tls_hosts: bool,
+    pub nodeport_source: bool,
+    pub node_address_type: String,
+    pub apex_prefer: RecordType,
+    pub wildcard_hostname: Option<String>,
+    pub wildcard_service: Option<String>,
+    pub publish_delay: Option<Duration>,
+    pub delete_grace: Option<Duration>,
+    pub deletable_types: Vec<RecordType>,
+    pub custom_hostnames: bool,
+    pub once: bool,
+    pub leader_election: bool,
+    pub name_exclude: Option<Regex>,
+    pub lease: Option<(String, String)>,
+    pub lease_duration: Duration,
+    pub lease_identity: String,
+    pub debug_reconcile: bool,
+}
+
+fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    }
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("zones", &self.zones)
+            .field("cf_token", &redact(&self.cf_token))
+            .field("pool_max_idle_per_host", &self.pool_max_idle_per_host)
+            .field("pool_idle_timeout", &self.pool_idle_timeout)
+            .field("ownership", &self.ownership)
+            .field("owner_id", &self.owner_id)
+            .field("migrate_bare_markers", &self.migrate_bare_markers)
+            .field("batch", &self.batch)
+            .field("batch_size", &self.batch_size)
+            .field("ingress_classes", &self.ingress_classes)
+            .field("max_records", &self.max_records)
+            .field("require_ready", &self.require_ready)
+            .field("listen_addr", &self.listen_addr)
+            .field("proxied_default", &self.proxied_default)
+            .field("cluster_name", &self.cluster_name)
+            .field("list_zones", &self.list_zones)
+            .field("export_zone", &self.export_zone)
+            .field("log_format", &self.log_format)
+            .field("reconcile_timeout", &self.reconcile_timeout)
+            .field("ready_max_failures", &self.ready_max_failures)
+            .field("ready_max_age", &self.ready_max_age)
+            .field("watch_resources", &self.watch_resources)
+            .field("ingressroute_service", &self.ingressroute_service)
+            .field("manage_since", &self.manage_since)
+            .field("retry_budget", &self.retry_budget)
+            .field("soft_delete", &self.soft_delete)
+            .field("paused", &self.paused)
+            .field("pause_configmap", &self.pause_configmap)
+            .field("auto_hostname_template", &self.auto_hostname_template)
+            .field("txt_marker_prefix", &self.txt_marker_prefix)
+            .field("reverse_zones", &self.reverse_zones)
+            .field("txt_marker_fields", &self.txt_marker_fields)
+            .field("webhook", &self.webhook)
+            .field("history_size", &self.history_size)
+            .field("stamp_resource_version", &self.stamp_resource_version)
+            .field("adopt", &self.adopt)
+            .field("max_deletes", &self.max_deletes)
+            .field("publish_service", &self.publish_service)
+            .field("include_tls_hosts", &self.include_tls_hosts)
+            .field("nodeport_source", &self.nodeport_source)
+            .field("node_address_type", &self.node_address_type)
+            .field("apex_prefer", &self.apex_prefer)
+            .field("wildcard_hostname", &self.wildcard_hostname)
+            .field("wildcard_service", &self.wildcard_service)
+            .field("publish_delay", &self.publish_delay)
+            .field("delete_grace", &self.delete_grace)
+            .field("deletable_types", &self.deletable_types)
+            .field("custom_hostnames", &self.custom_hostnames)
+            .field("once", &self.once)
+            .field("leader_election", &self.leader_election)
+            .field("name_exclude", &self.name_exclude.as_ref().map(Regex::as_str))
+            .field("lease", &self.lease)
+            .field("lease_duration", &self.lease_duration)
+            .field("lease_identity", &self.lease_identity)
+            .field("debug_reconcile", &self.debug_reconcile)
+            .finish()
+    }
+}
+
+// Reads the settings from the environment, collecting every malformed value instead of failing on the first.
+struct Env<'a> {
+    vars: &'a dyn Fn(&str) -> Option<String>,
+    args: &'a [String],
+    problems: RefCell<Vec<String>>,
+}
+
+impl Env<'_> {
+    fn var(&self, name: &str) -> Option<String> {
+        (self.vars)(name)
+    }
+
+    // Settings without a default are unset when empty.
+    fn string(&self, name: &str) -> Option<String> {
+        self.var(name).filter(|value| !value.is_empty())
+    }
+
+    fn problem(&self, problem: String) {
+        self.problems.borrow_mut().push(problem);
+    }
+
+    fn invalid(&self, name: &str, reason: impl Display) {
+        self.problem(format!("invalid {} environment variable: {}", name, reason));
+    }
+
+    fn required(&self, name: &str) -> String {
+        self.var(name).unwrap_or_else(|| {
+            self.problem(format!("{} environment variable not set", name));
+            String::new()
+        })
+    }
+
+    fn maybe<T: FromStr>(&self, name: &str) -> Option<T>
+        where T::Err: Display {
+        match self.var(name)?.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.invalid(name, err);
+                None
+            }
+        }
+    }
+
+    fn optional<T: FromStr>(&self, name: &str, default: T) -> T
+        where T::Err: Display {
+        self.maybe(name).unwrap_or(default)
+    }
+
+    fn flag(&self, name: &str, arg: &str) -> bool {
+        self.optional(name, false) || self.args.iter().any(|a| a == arg)
+    }
+
+    fn record_types(&self, name: &str) -> Vec<RecordType> {
+        self.list(name).into_iter()
+            .filter_map(|value| match RecordType::from(value.to_uppercase()) {
+                RecordType::Other(_) => {
+                    self.invalid(name, format!("{} is not a record type", value));
+                    None
+                }
+                record_type => Some(record_type)
+            })
+            .collect()
+    }
+
+    fn list(&self, name: &str) -> Vec<String> {
+        self.var(name).unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    // One of the choices, the first one when unset.
+    fn choice(&self, name: &str, choices: &[&'static str]) -> &'static str {
+        let value = match self.var(name) {
+            Some(value) => value,
+            None => return choices[0]
+        };
+        match choices.iter().find(|choice| **choice == value) {
+            Some(choice) => choice,
+            None => {
+                self.invalid(name, format!("{}, expected {}", value, choices.join(" or ")));
+                choices[0]
+            }
+        }
+    }
+
+    fn namespaced(&self, name: &str) -> Option<String> {
+        let value = self.string(name)?;
+        if !value.contains('/') {
+            self.invalid(name, format!("{}, expected namespace/name", value));
+            return None;
+        }
+        Some(value)
+    }
+
+    fn ownership(&self) -> Ownership {
+        match self.choice("OWNERSHIP", &["txt", "comment"]) {
+            "comment" => Ownership::Comment(self.optional("OWNERSHIP_COMMENT_PREFIX", "k8s:".to_string())),
+            _ => Ownership::Txt
+        }
+    }
+
+    // `ZONE_NAME` lists the zones and `REVERSE_ZONES` the reverse zones receiving PTR records, each reconciled
+    // every `RECONCILE_INTERVAL_SECONDS` unless `ZONE_INTERVALS` overrides it with `zone=seconds` pairs.
+    fn zones(&self, list_zones: bool) -> Vec<ZoneConfig> {
+        let names = self.list("ZONE_NAME");
+        if names.is_empty() && !list_zones {
+            self.problem("ZONE_NAME environment variable not set".into());
+        }
+
+        let interval = Duration::from_secs(self.optional("RECONCILE_INTERVAL_SECONDS", 60));
+        let mut zones: Vec<ZoneConfig> = names.into_iter()
+            .map(|name| ZoneConfig { name, interval, reverse: false })
+            .chain(self.list("REVERSE_ZONES").into_iter().map(|name| ZoneConfig { name, interval, reverse: true }))
+            .collect();
+        for item in self.list("ZONE_INTERVALS") {
+            let (name, seconds) = match item.split_once('=')
+                .and_then(|(name, seconds)| Some((name, seconds.parse().ok()?))) {
+                Some(interval) => interval,
+                None => {
+                    self.invalid("ZONE_INTERVALS", format!("{}, expected zone=seconds", item));
+                    continue;
+                }
+            };
+            match zones.iter_mut().find(|zone| zone.name == name) {
+                Some(zone) => zone.interval = Duration::from_secs(seconds),
+                None => self.invalid("ZONE_INTERVALS", format!("{} is not in ZONE_NAME or REVERSE_ZONES", name))
+            }
+        }
+        zones
+    }
+
+    // `WATCH_RESOURCES` lists the watched kinds, Services and Ingresses when unset. `WATCH_INGRESSROUTE` adds
+    // IngressRoutes to either and `NODEPORT_SOURCE` Nodes.
+    fn watch_resources(&self) -> Vec<String> {
+        let mut resources = self.list("WATCH_RESOURCES");
+        if resources.is_empty() {
+            resources = vec!["service".into(), "ingress".into()];
+        }
+        for kind in resources.iter().filter(|kind| !WATCHABLE_RESOURCES.contains(&kind.as_str())) {
+            self.invalid("WATCH_RESOURCES", format!("{}, expected a list of {}", kind,
+                                                    WATCHABLE_RESOURCES.join(", ")));
+        }
+        resources.retain(|kind| WATCHABLE_RESOURCES.contains(&kind.as_str()));
+        if self.optional("WATCH_INGRESSROUTE", false) && !resources.iter().any(|kind| kind == "ingressroute") {
+            resources.push("ingressroute".into());
+        }
+        if self.optional("NODEPORT_SOURCE", false) && !resources.iter().any(|kind| kind == "node") {
+            resources.push("node".into());
+        }
+        resources
+    }
+
+    // `TXT_MARKER_FIELDS` adds the controller version and the creation time to new ownership markers.
+    fn txt_marker_fields(&self) -> Vec<String> {
+        let mut fields = self.list("TXT_MARKER_FIELDS");
+        for field in fields.iter().filter(|field| !MARKER_FIELDS.contains(&field.as_str())) {
+            self.invalid("TXT_MARKER_FIELDS", format!("{}, expected a list of {}", field, MARKER_FIELDS.join(", ")));
+        }
+        fields.retain(|field| MARKER_FIELDS.contains(&field.as_str()));
+        fields
+    }
+}
+
+const WATCHABLE_RESOURCES: [&str; 4] = ["service", "ingress", "ingressroute", "node"];
+
+const MARKER_FIELDS: [&str; 2] = ["version", "created"];
+
+impl Config {
+    pub fn from_env() -> Self {
+        let args: Vec<String> = env::args().collect();
+        Self::from_vars(&|name| env::var(name).ok(), &args)
+            .unwrap_or_else(|problems| panic!("invalid configuration:\n  {}", problems.join("\n  ")))
+    }
+
+    /// The configuration of the variables and command line arguments, or every problem with them.
+    pub fn from_vars(vars: &dyn Fn(&str) -> Option<String>, args: &[String]) -> Result<Self, Vec<String>> {
+        let env = Env { vars, args, problems: RefCell::new(Vec::new()) };
+        let config = Self::parse(&env);
+        let mut problems = env.problems.into_inner();
+        problems.extend(config.problems());
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn parse(env: &Env) -> Self {
+        let list_zones = env.flag("LIST_ZONES", "--list-zones");
+
+        Self {
+            zones: env.zones(list_zones),
+            cf_token: env.required("CF_TOKEN"),
+            pool_max_idle_per_host: env.optional("CF_POOL_MAX_IDLE_PER_HOST", 8),
+            pool_idle_timeout: Duration::from_secs(env.optional("CF_POOL_IDLE_TIMEOUT_SECONDS", 90)),
+            ownership: env.ownership(),
+            owner_id: env.string("OWNER_ID"),
+            migrate_bare_markers: env.optional("MIGRATE_BARE_MARKERS", false),
+            batch: env.optional("CF_BATCH", false),
+            batch_size: env.optional("CF_BATCH_SIZE", 200),
+            ingress_classes: env.list("INGRESS_CLASS"),
+            max_records: env.maybe("MAX_RECORDS"),
+            require_ready: env.optional("REQUIRE_READY", false),
+            listen_addr: env.optional("LISTEN_ADDR", SocketAddr::from(([0, 0, 0, 0], 8080))),
+            proxied_default: env.optional("PROXIED_DEFAULT", false),
+            cluster_name: env.string("CLUSTER_NAME"),
+            list_zones,
+            export_zone: env.flag("EXPORT_ZONE", "--export-zone"),
+            log_format: match env.choice("LOG_FORMAT", &["pretty", "json"]) {
+                "json" => LogFormat::Json,
+                _ => LogFormat::Pretty
+            },
+            reconcile_timeout: Duration::from_secs(env.optional("RECONCILE_TIMEOUT_SECONDS", 120)),
+            ready_max_failures: env.optional("READY_MAX_FAILURES", 3),
+            ready_max_age: Duration::from_secs(env.optional("READY_MAX_AGE_SECONDS", 600)),
+            watch_resources: env.watch_resources(),
+            ingressroute_service: env.optional("INGRESSROUTE_SERVICE", "traefik/traefik".to_string()),
+            manage_since: env.string("MANAGE_SINCE")
+                .and_then(|since| DateTime::parse_from_rfc3339(&since)
+                    .map_err(|err| env.invalid("MANAGE_SINCE", err))
+                    .ok()),
+            retry_budget: env.optional("CF_RETRY_BUDGET", 0.1),
+            soft_delete: env.optional("SOFT_DELETE", false),
+            paused: env.optional("PAUSED", false),
+            pause_configmap: env.namespaced("PAUSE_CONFIGMAP")
+                .and_then(|name| name.split_once('/').map(|(namespace, name)| (namespace.into(), name.into()))),
+            auto_hostname_template: env.string("AUTO_HOSTNAME_TEMPLATE"),
+            txt_marker_prefix: env.string("TXT_MARKER_PREFIX"),
+            reverse_zones: env.list("REVERSE_ZONES"),
+            txt_marker_fields: env.txt_marker_fields(),
+            webhook: env.optional("WEBHOOK", false),
+            history_size: env.optional("HISTORY_SIZE", 50),
+            stamp_resource_version: env.optional("STAMP_RESOURCE_VERSION", false),
+            // A record whose marker was deleted looks like any other unmanaged record, so reclaiming it is
+            // adopting it.
+            adopt: env.optional("ADOPT", false) || env.optional("RECLAIM_ORPHANS", false),
+            max_deletes: env.maybe("MAX_DELETES_PER_RECONCILE"),
+            publish_service: env.namespaced("PUBLISH_SERVICE"),
+            include_tls_hosts: env.optional("INCLUDE_TLS_HOSTS", false),
+            nodeport_source: env.optional("NODEPORT_SOURCE", false),
+            node_address_type: env.choice("NODE_ADDRESS_TYPE", &["ExternalIP", "InternalIP"]).into(),
+            apex_prefer: match env.choice("APEX_PREFER", &["A", "CNAME"]) {
+                "CNAME" => RecordType::CNAME,
+                _ => RecordType::A
+            },
+            wildcard_hostname: env.string("WILDCARD_HOSTNAME"),
+            wildcard_service: env.string("WILDCARD_SERVICE"),
+            publish_delay: env.maybe("PUBLISH_DELAY_SECONDS").map(Duration::from_secs),
+            delete_grace: env.maybe("DELETE_GRACE_SECONDS").map(Duration::from_secs),
+            deletable_types: env.record_types("DELETABLE_TYPES"),
+            custom_hostnames: env.optional("CUSTOM_HOSTNAMES", false),
+            once: env.flag("ONCE", "--once"),
+            leader_election: env.optional("ENABLE_LEADER_ELECTION", false),
+            name_exclude: env.string("NAME_EXCLUDE_REGEX")
+                .and_then(|pattern| Regex::new(&pattern)
+                    .map_err(|err| env.invalid("NAME_EXCLUDE_REGEX", err))
+                    .ok()),
+            // Off by default, overlapping `ONCE` runs only wait for each other with a `LEASE_NAME`. Replicas with
+            // `ENABLE_LEADER_ELECTION` share it to elect the one that reconciles.
+            lease: env.string("LEASE_NAME")
+                .map(|name| (env.optional("LEASE_NAMESPACE", "default".to_string()), name)),
+            lease_duration: Duration::from_secs(env.optional("LEASE_DURATION_SECONDS", 60)),
+            lease_identity: env.string("HOSTNAME")
+                .unwrap_or_else(|| format!("{}-{}", APP_NAME, std::process::id())),
+            debug_reconcile: env.optional("DEBUG_RECONCILE", false),
+        }
+    }
+
+    /// Every inconsistency of the parsed settings, so all of them can be fixed at once. Values that don't parse at
+    /// all are reported while parsing already.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, zone) in self.zones.iter().enumerate() {
+            if !valid_hostname(&zone.name) {
+                problems.push(format!("ZONE_NAME: {} is not a valid zone name", zone.name));
+            }
+            if self.zones[..i].iter().any(|other| other.name == zone.name) {
+                problems.push(format!("ZONE_NAME: {} is listed more than once", zone.name));
+            }
+        }
+        for zone in &self.reverse_zones {
+            if !zone.ends_with(".in-addr.arpa") && !zone.ends_with(".ip6.arpa") {
+                problems.push(format!("REVERSE_ZONES: {} is not below in-addr.arpa or ip6.arpa", zone));
+            }
+        }
+        if matches!(&self.owner_id, Some(owner_id) if owner_id.contains(',') || owner_id.contains('=')) {
+            problems.push("OWNER_ID: must not contain ',' or '='".into());
+        }
+        match (&self.ownership, &self.txt_marker_prefix) {
+            (Ownership::Comment(_), Some(_)) =>
+                problems.push("TXT_MARKER_PREFIX: has no effect with OWNERSHIP=comment".into()),
+            (_, Some(prefix)) if !valid_record_name(prefix) =>
+                problems.push(format!("TXT_MARKER_PREFIX: {} is not a valid DNS label", prefix)),
+            _ => {}
+        }
+        let txt = matches!(self.ownership, Ownership::Txt);
+        if txt && self.txt_marker_prefix.is_none() && self.apex_prefer == RecordType::CNAME {
+            problems.push("APEX_PREFER: CNAME needs OWNERSHIP=comment or TXT_MARKER_PREFIX, a TXT marker can't \
+                           share the name of a CNAME".into());
+        }
+        match (&self.wildcard_hostname, self.wildcard_service.as_ref().or(self.publish_service.as_ref())) {
+            (Some(hostname), _) if !hostname.starts_with("*.") =>
+                problems.push(format!("WILDCARD_HOSTNAME: {} does not start with *.", hostname)),
+            (Some(_), None) => problems.push("WILDCARD_HOSTNAME: needs WILDCARD_SERVICE or PUBLISH_SERVICE".into()),
+            _ => {}
+        }
+        if matches!(&self.wildcard_service, Some(service) if !service.contains('/')) {
+            problems.push("WILDCARD_SERVICE: expected namespace/name".into());
+        }
+        if self.ready_max_failures == 0 {
+            problems.push("READY_MAX_FAILURES: must be positive, the controller would never be ready".into());
+        }
+        if self.batch_size == 0 {
+            problems.push("CF_BATCH_SIZE: must be positive".into());
+        }
+        if self.max_deletes == Some(0) {
+            problems.push("MAX_DELETES_PER_RECONCILE: must be positive, stale records would never be deleted".into());
+        }
+        if self.leader_election && self.lease.is_none() {
+            problems.push("ENABLE_LEADER_ELECTION: requires LEASE_NAME".into());
+        }
+        if self.lease.is_some() && self.lease_duration < Duration::from_secs(3) {
+            problems.push("LEASE_DURATION_SECONDS: must be at least 3, the lease would expire between renewals".into());
+        }
+        if matches!(&self.cluster_name, Some(name) if HeaderValue::from_str(name).is_err()) {
+            problems.push("CLUSTER_NAME: must be printable ASCII, it's sent in the User-Agent".into());
+        }
+        if self.retry_budget < 0.0 {
+            problems.push(format!("CF_RETRY_BUDGET: {} must not be negative", self.retry_budget));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A valid configuration of `example.com` with the variables set on top, never reading the environment.
+    pub(crate) fn for_test(vars: &[(&str, &str)]) -> Self {
+        Self::from_vars(&test_vars(vars), &[]).unwrap()
+    }
+}
+
+#[cfg(test)]
+fn test_vars<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+    move |name| vars.iter().rev()
+        .chain(&[("ZONE_NAME", "example.com"), ("CF_TOKEN", "token"), ("HOSTNAME", "test")])
+        .find(|(var, _)| *var == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(vars: &[(&str, &str)]) -> Vec<String> {
+        Config::from_vars(&test_vars(vars), &[]).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        assert_eq!(problems(&[("OWNERSHIP", "comment"), ("LOG_FORMAT", "json")]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lists_every_malformed_value() {
+        let problems = problems(&[
+            ("OWNERSHIP", "dns"),
+            ("LOG_FORMAT", "xml"),
+            ("APEX_PREFER", "AAAA"),
+            ("NODE_ADDRESS_TYPE", "Hostname"),
+            ("WATCH_RESOURCES", "service,pod"),
+            ("MAX_RECORDS", "many"),
+            ("NAME_EXCLUDE_REGEX", "("),
+        ]);
+        assert_eq!(problems.len(), 7, "{:?}", problems);
+        assert!(problems.contains(&"invalid OWNERSHIP environment variable: dns, expected txt or comment".into()));
+        assert!(problems.contains(&"invalid LOG_FORMAT environment variable: xml, expected pretty or json".into()));
+        assert!(problems.iter().any(|p| p.starts_with("invalid APEX_PREFER environment variable: AAAA")));
+        assert!(problems.iter().any(|p| p.starts_with("invalid NODE_ADDRESS_TYPE environment variable: Hostname")));
+        assert!(problems.iter().any(|p| p.starts_with("invalid WATCH_RESOURCES environment variable: pod")));
+        assert!(problems.iter().any(|p| p.starts_with("invalid MAX_RECORDS environment variable:")));
+        assert!(problems.iter().any(|p| p.starts_with("invalid NAME_EXCLUDE_REGEX environment variable:")));
+    }
+
+    #[test]
+    fn lists_malformed_values_with_inconsistencies() {
+        let problems = problems(&[
+            ("ZONE_NAME", "example.com,example.com"),
+            ("ZONE_INTERVALS", "other.com=30,example.com"),
+            ("READY_MAX_FAILURES", "0"),
+            ("OWNER_ID", "a=b"),
+            ("APEX_PREFER", "CNAME"),
+        ]);
+        assert_eq!(problems, vec![
+            "invalid ZONE_INTERVALS environment variable: other.com is not in ZONE_NAME or REVERSE_ZONES",
+            "invalid ZONE_INTERVALS environment variable: example.com, expected zone=seconds",
+            "ZONE_NAME: example.com is listed more than once",
+            "OWNER_ID: must not contain ',' or '='",
+            "APEX_PREFER: CNAME needs OWNERSHIP=comment or TXT_MARKER_PREFIX, a TXT marker can't share the name of a \
+             CNAME",
+            "READY_MAX_FAILURES: must be positive, the controller would never be ready",
+        ]);
+    }
+
+    #[test]
+    fn invalid_name_exclude_regex() {
+        let invalid = problems(&[("NAME_EXCLUDE_REGEX", "internal\\.(")]);
+        assert_eq!(invalid.len(), 1, "{:?}", invalid);
+        assert!(invalid[0].starts_with("invalid NAME_EXCLUDE_REGEX environment variable: regex parse error"),
+                "{:?}", invalid);
+        assert!(problems(&[("NAME_EXCLUDE_REGEX", "^internal\\.")]).is_empty());
+    }
+
+    #[test]
+    fn txt_marker_prefix_allows_underscores() {
+        assert!(problems(&[("TXT_MARKER_PREFIX", "_kube-cf-dns")]).is_empty());
+        assert!(problems(&[("TXT_MARKER_PREFIX", "owner_marker")]).is_empty());
+        assert_eq!(problems(&[("TXT_MARKER_PREFIX", "-kube-cf-dns")]),
+                   vec!["TXT_MARKER_PREFIX: -kube-cf-dns is not a valid DNS label"]);
+    }
+
+    #[test]
+    fn unprintable_cluster_name() {
+        assert_eq!(problems(&[("CLUSTER_NAME", "prod\n")]),
+                   vec!["CLUSTER_NAME: must be printable ASCII, it's sent in the User-Agent"]);
+        assert!(problems(&[("CLUSTER_NAME", "prod-eu")]).is_empty());
+    }
+
+    #[test]
+    fn unknown_deletable_types() {
+        assert_eq!(problems(&[("DELETABLE_TYPES", "a,AA,txt")]),
+                   vec!["invalid DELETABLE_TYPES environment variable: AA is not a record type"]);
+        let config = Config::for_test(&[("DELETABLE_TYPES", "a, txt")]);
+        assert_eq!(config.deletable_types, vec![RecordType::A, RecordType::TXT]);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::for_test(&[]);
+        assert_eq!(config.zones[0].name, "example.com");
+        assert_eq!(config.zones[0].interval, Duration::from_secs(60));
+        assert_eq!(config.watch_resources, vec!["service", "ingress"]);
+        assert!(matches!(config.ownership, Ownership::Txt));
+    }
+
+    #[test]
+    fn missing_required_variables() {
+        let vars = |name: &str| (name == "OWNERSHIP").then(|| "comment".to_string());
+        assert_eq!(Config::from_vars(&vars, &[]).err().unwrap(), vec![
+            "ZONE_NAME environment variable not set",
+            "CF_TOKEN environment variable not set",
+        ]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::api::{Api, PostParams};
+
+use crate::clock::Clock;
+
+/// A coordination.k8s.io Lease held by at most one instance at a time. Another instance can only take it over once
+/// the holder stopped renewing it for the lease duration.
+#[derive(Clone)]
+pub struct LeaseLock {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    duration: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl LeaseLock {
+    pub fn new(client: kube::Client, namespace: &str, name: &str, identity: &str, duration: Duration,
+               clock: Arc<dyn Clock>) -> Self {
+        Self {
+            api: Api::namespaced(client, namespace),
+            name: name.into(),
+            identity: identity.into(),
+            duration,
+            clock,
+        }
+    }
+
+    /// Takes or renews the lease, false while it's held by another instance.
+    pub async fn try_acquire(&self) -> Result<bool, kube::Error> {
+        let now = DateTime::<Utc>::from(self.clock.now());
+        let lease = match self.api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(err)) if err.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(self.spec(now, now, 0)),
+                };
+                return conflict_is_lost(self.api.create(&PostParams::default(), &lease).await);
+            }
+            Err(err) => return Err(err)
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let ours = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        let held = matches!(&spec.holder_identity, Some(holder) if !holder.is_empty());
+        let expired = match (&spec.renew_time, spec.lease_duration_seconds) {
+            (Some(MicroTime(renewed)), Some(seconds)) => *renewed + chrono::Duration::seconds(seconds.into()) < now,
+            _ => true
+        };
+        if held && !ours && !expired {
+            return Ok(false);
+        }
+
+        let (acquired, transitions) = match (ours, &spec.acquire_time) {
+            (true, Some(MicroTime(acquired))) => (*acquired, spec.lease_transitions.unwrap_or(0)),
+            _ => (now, spec.lease_transitions.unwrap_or(0) + 1)
+        };
+        // The resource version of the listed lease makes the replace fail if another instance changed it meanwhile.
+        let lease = Lease {
+            metadata: lease.metadata,
+            spec: Some(self.spec(now, acquired, transitions)),
+        };
+        conflict_is_lost(self.api.replace(&self.name, &PostParams::default(), &lease).await)
+    }
+
+    /// Gives up the lease if we hold it, so the next instance doesn't have to wait for it to expire.
+    pub async fn release(&self) -> Result<(), kube::Error> {
+        let mut lease = self.api.get(&self.name).await?;
+        let ours = matches!(&lease.spec, Some(LeaseSpec { holder_identity: Some(holder), .. })
+            if *holder == self.identity);
+        if !ours {
+            return Ok(());
+        }
+        if let Some(spec) = &mut lease.spec {
+            spec.holder_identity = None;
+            spec.renew_time = None;
+        }
+        conflict_is_lost(self.api.replace(&self.name, &PostParams::default(), &lease).await).map(|_| ())
+    }
+
+    /// How often a holder renews the lease, well within its duration.
+    pub fn renew_interval(&self) -> Duration {
+        self.duration / 3
+    }
+
+    /// How much longer a holder that last renewed the lease at `renewed` may keep acting as its holder. Once it's
+    /// zero another instance may have taken the lease over.
+    pub fn remaining(&self, renewed: SystemTime) -> Duration {
+        let held = self.clock.now().duration_since(renewed).unwrap_or_default();
+        self.duration.checked_sub(held).unwrap_or_default()
+    }
+
+    fn spec(&self, now: DateTime<Utc>, acquired: DateTime<Utc>, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.duration.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquired)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(transitions),
+        }
+    }
+}
+
+// Another instance took the lease between us reading and writing it.
+fn conflict_is_lost(result: Result<Lease, kube::Error>) -> Result<bool, kube::Error> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::UNIX_EPOCH;
+
+    use crate::clock::TestClock;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn renew_deadline_is_the_lease_duration() {
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+        let config = kube::Config::new("http://127.0.0.1:1".parse().unwrap());
+        let lease = LeaseLock::new(kube::Client::try_from(config).unwrap(), "default", "dns", "test",
+                                   Duration::from_secs(60), clock.clone());
+        let renewed = clock.now();
+        assert_eq!(lease.remaining(renewed), Duration::from_secs(60));
+
+        clock.advance(lease.renew_interval() * 2);
+        assert_eq!(lease.remaining(renewed), Duration::from_secs(20));
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(lease.remaining(renewed), Duration::ZERO);
+    }
+}
@@ -1,6 +1,60 @@
 pub mod api;
+pub mod clock;
+pub mod config;
+pub mod lease;
+pub mod metrics;
 pub mod resource;
 pub mod plan;
+pub mod resolve;
+pub mod server;
+pub mod traefik;
+pub mod webhook;
 
 pub const APP_NAME: &str = "kube-cloudflare-dns";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
 pub const HOSTNAME_LABEL: &str = "kube-cloudflare-dns.github.com/hostname";
+/// TTL of the published records, either `auto` or 60 to 86400 seconds. Automatic when unset.
+pub const TTL_LABEL: &str = "kube-cloudflare-dns.github.com/ttl";
+/// Whether A/AAAA/CNAME records go through the Cloudflare proxy, `PROXIED_DEFAULT` when unset. Other record
+/// types are never proxied.
+pub const PROXIED_LABEL: &str = "kube-cloudflare-dns.github.com/proxied";
+/// CAA record published next to the hostname, in zone file form, e.g. `0 issue "letsencrypt.org"`.
+pub const CAA_LABEL: &str = "kube-cloudflare-dns.github.com/caa";
+/// Resolve LoadBalancer hostnames to A/AAAA records instead of ignoring them. Lookups happen once per
+/// reconcile, so records only follow DNS changes of the LoadBalancer hostname as often as the controller
+/// reconciles.
+pub const RESOLVE_HOSTNAME_LABEL: &str = "kube-cloudflare-dns.github.com/resolve-hostname";
+/// Comma separated `address=weight` pairs for resources with several LoadBalancer addresses. Cloudflare DNS
+/// has no per-record weight, so records are published in descending weight order and addresses with weight 0
+/// are not published at all. Unlisted addresses have weight 1.
+pub const WEIGHTS_LABEL: &str = "kube-cloudflare-dns.github.com/weights";
+/// Publish an Ingress as a CNAME to its LoadBalancer status hostname instead of A/AAAA records of its status IPs.
+/// Cloudflare does not allow other records next to a CNAME, so this needs `OWNERSHIP=comment` or markers moved
+/// aside with `TXT_MARKER_PREFIX`, CNAMEs are skipped otherwise.
+pub const USE_HOSTNAME_LABEL: &str = "kube-cloudflare-dns.github.com/use-hostname";
+/// Publish an SRV record `_<port>._<protocol>.<hostname>` pointing at the hostname for every named Service port.
+pub const SRV_FROM_PORTS_LABEL: &str = "kube-cloudflare-dns.github.com/srv-from-ports";
+/// Stop publishing the resource's hostnames while leaving their existing records in place. Removing the hostname
+/// annotation or the resource instead deletes the records.
+pub const DISABLED_LABEL: &str = "kube-cloudflare-dns.github.com/disabled";
+/// Publish a single record with this content instead of the resource addresses. `${name}`, `${namespace}` and
+/// `${ip}` (the first address that is an IP) are substituted, e.g. `${name}.internal.example.com`. The result must
+/// be an IP, published as A/AAAA, or a hostname, published as CNAME like with `USE_HOSTNAME_LABEL`.
+pub const CONTENT_TEMPLATE_LABEL: &str = "kube-cloudflare-dns.github.com/content-template";
+/// Which LoadBalancer status addresses are published: `all` (default), `first`, `ipv4` or `ipv6`.
+pub const LB_SELECT_LABEL: &str = "kube-cloudflare-dns.github.com/lb-select";
+/// Publish the requested `spec.loadBalancerIP` of a LoadBalancer Service while its status has no address yet.
+/// The provider may not grant the requested IP, so this is opt-in.
+pub const USE_REQUESTED_IP_LABEL: &str = "kube-cloudflare-dns.github.com/use-requested-ip";
+/// Publish the LoadBalancer addresses a Service reports with `ipMode: Proxy` as well. They are skipped by default,
+/// traffic to them only works through the cluster's proxy.
+pub const PUBLISH_PROXY_IPS_LABEL: &str = "kube-cloudflare-dns.github.com/publish-proxy-ips";
+/// Cloudflare record settings as a JSON object, e.g. `{"flatten_cname": true}`. Only the given settings are
+/// compared with the published records.
+pub const SETTINGS_LABEL: &str = "kube-cloudflare-dns.github.com/settings";
+/// Expected record type of the addresses, `A`, `AAAA` or `CNAME`. Addresses of another type are skipped, CNAMEs
+/// need the same ownership settings as `USE_HOSTNAME_LABEL`.
+pub const RECORD_TYPE_LABEL: &str = "kube-cloudflare-dns.github.com/record-type";
+/// Zone to publish the resource's records in instead of the most specific zone containing them.
+pub const ZONE_LABEL: &str = "kube-cloudflare-dns.github.com/zone";
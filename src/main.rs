@@ -1,127 +1,759 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
-use std::iter;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Debug, Display};
+use std::future::Future;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
-use anyhow::anyhow;
 use futures::TryStreamExt;
-use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::core::v1::{ConfigMap, Node};
 use k8s_openapi::api::networking::v1::Ingress;
 use kube::api::ListParams;
+use thiserror::Error;
 use serde::de::DeserializeOwned;
-use tokio::sync::mpsc::{channel, Sender};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use kube_cloudflare_dns::api::CfApi;
-use kube_cloudflare_dns::plan::{compute_records, dedupe_records, plan};
-use kube_cloudflare_dns::resource::{ResourceKey, WatchedResource};
+use kube_cloudflare_dns::{APP_NAME, GIT_COMMIT, VERSION};
+use kube_cloudflare_dns::api::{CfApi, CfError, Record};
+use kube_cloudflare_dns::clock::{Clock, SystemClock};
+use kube_cloudflare_dns::config::{Config, LogFormat, ZoneConfig};
+use kube_cloudflare_dns::lease::LeaseLock;
+use kube_cloudflare_dns::metrics::Metrics;
+use kube_cloudflare_dns::plan::{PlanAction, PlannedChange, RecordTimes, custom_hostnames, expected_records,
+                                    exceeded_max_records, host_record_names, hostnames_to_resolve,
+                                    missing_custom_hostnames, scoped_changes, stamp_markers, untouched_hostnames,
+                                    zone_for};
+use kube_cloudflare_dns::resolve::resolve_hostnames;
+use kube_cloudflare_dns::server::{serve, ReconcileRequest, ServerState};
+use kube_cloudflare_dns::resource::{ResourceKey, WatchedResource, WatchedService};
+use kube_cloudflare_dns::traefik::IngressRoute;
 
-async fn watcher<T>(client: kube::Client, watched_resources: Arc<Mutex<HashMap<ResourceKey, WatchedResource>>>,
-                    changed: Sender<()>)
+const WATCH_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const WATCH_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const KUBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(300);
+
+type Resources = Arc<Mutex<HashMap<ResourceKey, WatchedResource>>>;
+type SpawnWatcher = fn(kube::Client, Resources, Sender<()>) -> JoinHandle<()>;
+
+async fn watcher<T>(client: kube::Client, watched_resources: Resources, changed: Sender<()>)
     where T: kube::Resource + Clone + DeserializeOwned + Debug + Send + 'static,
           <T as kube::Resource>::DynamicType: Default,
           WatchedResource: From<T> {
     let api = kube::Api::<T>::all(client);
-    let stream = kube_runtime::watcher(api, ListParams::default());
-    let mut stream = Box::pin(stream);
+    let kind = T::kind(&Default::default()).to_string();
+    let mut backoff = WATCH_MIN_BACKOFF;
     loop {
-        use kube_runtime::watcher::Event::*;
-
-        #[allow(unused_must_use)]
-        match stream.try_next().await {
-            Ok(Some(event)) => match event {
-                Restarted(resources) => {
-                    let mut watched_resources = watched_resources.lock().await;
-                    for res in resources {
-                        let key = ResourceKey::from(&res);
-                        watched_resources.insert(key, res.into());
+        let mut stream = Box::pin(kube_runtime::watcher(api.clone(), ListParams::default()));
+        loop {
+            use kube_runtime::watcher::Event::*;
+
+            #[allow(unused_must_use)]
+            match stream.try_next().await {
+                Ok(Some(event)) => {
+                    backoff = WATCH_MIN_BACKOFF;
+                    match event {
+                        Restarted(resources) => {
+                            let mut watched_resources = watched_resources.lock().await;
+                            for res in resources {
+                                let key = ResourceKey::from(&res);
+                                watched_resources.insert(key, res.into());
+                            }
+                            changed.try_send(());
+                        }
+                        Applied(resource) => {
+                            let mut watched_resources = watched_resources.lock().await;
+                            watched_resources.insert(ResourceKey::from(&resource), resource.into());
+                            changed.try_send(());
+                        }
+                        Deleted(resource) => {
+                            let mut watched_resources = watched_resources.lock().await;
+                            watched_resources.remove(&ResourceKey::from(&resource));
+                            changed.try_send(());
+                        }
                     }
-                    changed.try_send(());
                 }
-                Applied(resource) => {
-                    let mut watched_resources = watched_resources.lock().await;
-                    watched_resources.insert(ResourceKey::from(&resource), resource.into());
-                    changed.try_send(());
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("watch error: {}", err);
+                    sleep(Duration::from_secs(30)).await;
                 }
-                Deleted(resource) => {
-                    let mut watched_resources = watched_resources.lock().await;
-                    watched_resources.remove(&ResourceKey::from(&resource));
-                    changed.try_send(());
+            }
+        }
+
+        info!("{} watch ended, resyncing in {:?}", kind, backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(WATCH_MAX_BACKOFF);
+    }
+}
+
+fn spawn_watcher<T>(client: kube::Client, watched_resources: Resources, changed: Sender<()>) -> JoinHandle<()>
+    where T: kube::Resource + Clone + DeserializeOwned + Debug + Send + 'static,
+          <T as kube::Resource>::DynamicType: Default,
+          WatchedResource: From<T> {
+    tokio::task::spawn(watcher::<T>(client, watched_resources, changed))
+}
+
+// The `paused` key of the ConfigMap pauses the controller when "true" and resumes it otherwise. Without the key
+// or the ConfigMap `PAUSED` applies.
+async fn pause_watcher(client: kube::Client, namespace: String, name: String, default: bool,
+                       paused: Arc<AtomicBool>) {
+    let api = kube::Api::<ConfigMap>::namespaced(client, &namespace);
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    let set = |value: bool| if paused.swap(value, Ordering::Relaxed) != value {
+        info!("Controller {} by ConfigMap {}/{}", if value { "paused" } else { "resumed" }, namespace, name);
+    };
+    loop {
+        let mut stream = Box::pin(kube_runtime::watcher(api.clone(), params.clone()));
+        loop {
+            use kube_runtime::watcher::Event::*;
+
+            match stream.try_next().await {
+                Ok(Some(event)) => {
+                    let config_map = match event {
+                        Applied(config_map) => Some(config_map),
+                        Deleted(_) => None,
+                        Restarted(config_maps) => config_maps.into_iter().next(),
+                    };
+                    set(config_map
+                        .and_then(|config_map| config_map.data)
+                        .and_then(|data| data.get("paused").map(|value| value == "true"))
+                        .unwrap_or(default));
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("watch error: {}", err);
+                    sleep(Duration::from_secs(30)).await;
                 }
             }
-            Ok(None) => {}
-            Err(err) => {
-                println!("watch error: {}", err);
-                sleep(Duration::from_secs(30)).await;
+        }
+        sleep(WATCH_MIN_BACKOFF).await;
+    }
+}
+
+#[derive(Error, Debug)]
+enum ReconcileError {
+    #[error("zone {0} not found")]
+    ZoneNotFound(String),
+    #[error(transparent)]
+    Cloudflare(#[from] CfError),
+    #[error("reconcile timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("{0} expected records exceed MAX_RECORDS={1}, skipping reconcile")]
+    TooManyRecords(usize, usize),
+}
+
+impl ReconcileError {
+    fn is_fatal(&self) -> bool {
+        match self {
+            ReconcileError::ZoneNotFound(_) => true,
+            ReconcileError::Cloudflare(err) => err.is_permission_error(),
+            ReconcileError::Timeout(_) | ReconcileError::TooManyRecords(..) => false
+        }
+    }
+}
+
+// What a reconcile plans for.
+struct Desired<'a> {
+    expected: &'a [Record],
+    disabled: &'a [String],
+    // Only the records of the host are reconciled, for `POST /debug/reconcile?host=<hostname>`.
+    host: Option<&'a str>,
+}
+
+async fn reconcile(cf_client: &CfApi, config: &Config, zone_name: &str, desired: &Desired<'_>, times: &RecordTimes,
+                   state: &ServerState) -> Result<Vec<PlannedChange>, ReconcileError> {
+    let expected = desired.expected;
+    if let Some(max) = exceeded_max_records(expected, config) {
+        return Err(ReconcileError::TooManyRecords(expected.len(), max));
+    }
+    let zone = cf_client.zone(zone_name).await?
+        .ok_or_else(|| ReconcileError::ZoneNotFound(zone_name.to_string()))?;
+    // An on-demand reconcile only lists the records of its host instead of the whole zone.
+    let actual = match desired.host {
+        Some(host) => {
+            let mut actual = Vec::new();
+            for name in host_record_names(host, expected, config) {
+                actual.extend(cf_client.list_records_by_name(&zone.id, &name, None).await?);
             }
+            actual
+        }
+        None => cf_client.records(&zone.id).await?
+    };
+    debug!("Actual: {:?}", actual);
+
+    let mut changes = scoped_changes(expected, &actual, desired.disabled, desired.host, config);
+    times.hold_back(&mut changes, state.clock.now(), config);
+    stamp_markers(&mut changes, state.clock.now(), config);
+    debug!("Plan: {:?}", changes);
+    let plan: Vec<PlanAction> = changes.iter().map(|change| change.action.clone()).collect();
+    let count = |f: fn(&PlanAction) -> bool| plan.iter().filter(|action| f(action)).count();
+    let scope = match desired.host {
+        Some(host) => format!("{} on demand", host),
+        None => zone_name.to_string()
+    };
+    info!("reconcile {}: {} expected, {} actual, {} add, {} update, {} delete", scope, expected.len(), actual.len(),
+          count(|a| matches!(a, PlanAction::Add(_))), count(|a| matches!(a, PlanAction::Update(_))),
+          count(|a| matches!(a, PlanAction::Delete(_))));
+    if desired.host.is_none() {
+        state.metrics.plan_size.observe(plan.len() as f64);
+        if let Some(debug) = state.debug.lock().await.get_mut(zone_name) {
+            debug.actual = actual;
+            debug.plan = changes.clone();
         }
     }
+
+    if state.paused.load(Ordering::Relaxed) {
+        info!("Paused, not applying the plan for {}", scope);
+        return Ok(changes);
+    }
+
+    apply(cf_client, config, &zone.id, &plan, state).await;
+    Ok(changes)
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let zone_name = std::env::var("ZONE_NAME").expect("ZONE_NAME environment variable not set");
-    let cf_token = std::env::var("CF_TOKEN").expect("CF_TOKEN environment variable not set");
+// With `CUSTOM_HOSTNAMES` Ingress hosts outside our zones are onboarded as Cloudflare for SaaS custom hostnames of
+// the first zone.
+async fn reconcile_custom_hostnames(cf_client: &CfApi, zone_name: &str, expected: &BTreeSet<String>,
+                                    state: &ServerState) -> Result<(), ReconcileError> {
+    let zone = cf_client.zone(zone_name).await?
+        .ok_or_else(|| ReconcileError::ZoneNotFound(zone_name.to_string()))?;
+    let actual = cf_client.list_custom_hostnames(&zone.id).await?;
+    let missing = missing_custom_hostnames(expected, &actual);
+    info!("custom hostnames {}: {} expected, {} actual, {} add", zone_name, expected.len(), actual.len(),
+          missing.len());
+    if state.paused.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    for hostname in missing {
+        match cf_client.create_custom_hostname(&zone.id, &hostname).await {
+            Ok(()) => info!("Created custom hostname {}", hostname),
+            Err(err) => error!("Creating custom hostname {}: {}", hostname, err)
+        }
+    }
+    Ok(())
+}
+
+async fn apply(cf_client: &CfApi, config: &Config, zone_id: &str, plan: &[PlanAction], state: &ServerState) {
+    if config.batch {
+        for (chunk, result) in cf_client.apply_batch(zone_id, plan, config.batch_size).await {
+            match result {
+                Ok(()) => chunk.iter().for_each(|change| state.metrics.applied(change)),
+                Err(err) => error!("{}", err)
+            }
+        }
+    } else {
+        for change in plan {
+            use kube_cloudflare_dns::plan::PlanAction::*;
 
-    let kube_client = kube::Client::try_default().await.unwrap();
-    let cf_client = CfApi::new(&cf_token);
+            let result = match change {
+                Add(record) => cf_client.create_or_update(zone_id, record).await,
+                Delete(record) => cf_client.delete_record(zone_id, &record.id).await,
+                Update(record) => cf_client.update_record(zone_id, record).await
+            };
+            match result {
+                Ok(()) => state.metrics.applied(change),
+                Err(err) => error!("{}", err)
+            }
+        }
+    }
+}
 
-    let resources = Arc::new(Mutex::new(HashMap::<ResourceKey, WatchedResource>::new()));
-    let (tx, mut rx) = channel(10);
+// Hands `POST /debug/reconcile` requests to the loop of the zone containing the host, which reconciles it right
+// away.
+async fn reconcile_requests(mut requests: Receiver<ReconcileRequest>, zones: HashMap<String, Sender<ReconcileRequest>>,
+                            config: Arc<Config>) {
+    while let Some((host, reply)) = requests.recv().await {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let zone = zone_for(&host, &config.zones)
+            .filter(|zone| !zone.reverse)
+            .and_then(|zone| zones.get(&zone.name));
+        let reply = match zone {
+            Some(zone) => match zone.send((host, reply)).await {
+                Ok(()) => continue,
+                Err(SendError((host, reply))) => {
+                    let _ = reply.send(Err(format!("zone of {} is not reconciled anymore", host)));
+                    continue;
+                }
+            },
+            None => reply
+        };
+        let _ = reply.send(Err(ReconcileError::ZoneNotFound(host).to_string()));
+    }
+}
+
+async fn next_request(requests: &mut Option<Receiver<ReconcileRequest>>) -> Option<ReconcileRequest> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => futures::future::pending().await
+    }
+}
+
+async fn list_zones(cf_client: &CfApi) {
+    match cf_client.zones().await {
+        Ok(zones) => for zone in zones {
+            println!("{}\t{}", zone.name, zone.id);
+        },
+        Err(err) => {
+            error!("Failed to list zones: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn export_zones(cf_client: &CfApi, config: &Config) {
+    let result = async {
+        let mut records = Vec::new();
+        for zone_config in &config.zones {
+            let zone = cf_client.zone(&zone_config.name).await?
+                .ok_or_else(|| ReconcileError::ZoneNotFound(zone_config.name.clone()))?;
+            records.extend(cf_client.records(&zone.id).await?);
+        }
+        Ok::<_, ReconcileError>(records)
+    }.await;
+
+    match result {
+        Ok(records) => println!("{}", serde_json::to_string_pretty(&records).unwrap()),
+        Err(err) => {
+            error!("Failed to export zone records: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn check_zone_access(cf_client: &CfApi, zone_name: &str) {
+    let result = async {
+        match cf_client.zone(zone_name).await? {
+            Some(zone) => cf_client.records(&zone.id).await.map(Some),
+            None => Ok(None)
+        }
+    }.await;
+
+    match result {
+        Ok(Some(_)) => info!("Verified access to zone {}", zone_name),
+        Ok(None) => panic!("zone {} not found, check ZONE_NAME and that CF_TOKEN has access to it", zone_name),
+        Err(err) if err.is_permission_error() =>
+            panic!("CF_TOKEN is not permitted to edit DNS records of zone {}: {}", zone_name, err),
+        Err(err) => warn!("Failed to verify access to zone {}, continuing: {}", zone_name, err)
+    }
+}
+
+// The API server is often briefly unreachable while the control plane or the node restarts, so connecting is
+// retried with backoff for a while before giving up.
+async fn connect_kube() -> kube::Client {
+    let connect = || async {
+        let client = kube::Client::try_default().await?;
+        client.apiserver_version().await?;
+        Ok::<_, kube::Error>(client)
+    };
+    match retry_connect(connect, sleep).await {
+        Ok(client) => client,
+        Err(err) => panic!("failed to connect to the Kubernetes API: {}", err)
+    }
+}
 
-    tokio::task::spawn(watcher::<Service>(kube_client.clone(), resources.clone(), tx.clone()));
-    tokio::task::spawn(watcher::<Ingress>(kube_client.clone(), resources.clone(), tx.clone()));
+// Calls `connect` until it succeeds, waiting with `wait` in between, or fails with the last error once the waits
+// add up to `KUBE_CONNECT_TIMEOUT`.
+async fn retry_connect<T, E, C, W>(mut connect: impl FnMut() -> C, mut wait: impl FnMut(Duration) -> W) -> Result<T, E>
+    where E: Display, C: Future<Output = Result<T, E>>, W: Future<Output = ()> {
+    let mut backoff = WATCH_MIN_BACKOFF;
+    let mut waited = Duration::ZERO;
+    loop {
+        match connect().await {
+            Ok(client) => return Ok(client),
+            Err(err) if waited >= KUBE_CONNECT_TIMEOUT => return Err(err),
+            Err(err) => warn!("Failed to connect to the Kubernetes API, retrying in {:?}: {}", backoff, err)
+        }
+        wait(backoff).await;
+        waited += backoff;
+        backoff = (backoff * 2).min(WATCH_MAX_BACKOFF);
+    }
+}
+
+// Logs go to stderr, so the output of `EXPORT_ZONE` and `LIST_ZONES` can be redirected as is.
+fn init_logging(format: &LogFormat) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+    match format {
+        LogFormat::Json => subscriber.json().init(),
+        LogFormat::Pretty => subscriber.init(),
+    }
+}
+
+// Keeps renewing the lease of a `ONCE` run or elected leader. Losing it, or failing to renew it for the lease
+// duration, means another instance may be applying changes as well, so the process stops right away and comes back
+// as a standby.
+async fn hold_lease(lease: LeaseLock, acquired: SystemTime, clock: Arc<dyn Clock>) {
+    let mut renewed = acquired;
+    loop {
+        sleep(lease.renew_interval().min(lease.remaining(renewed))).await;
+        let attempt = clock.now();
+        match timeout(lease.remaining(renewed), lease.try_acquire()).await {
+            Ok(Ok(true)) => renewed = attempt,
+            Ok(Ok(false)) => {
+                error!("Lost the lease to another instance, exiting");
+                std::process::exit(1);
+            }
+            Ok(Err(err)) => warn!("Failed to renew the lease: {}", err),
+            Err(_) => warn!("Timed out renewing the lease")
+        }
+        if lease.remaining(renewed) == Duration::ZERO {
+            error!("Failed to renew the lease within its duration, exiting");
+            std::process::exit(1);
+        }
+    }
+}
 
-    rx.recv().await;
-    rx.recv().await;
+// Returns when the lease was last acquired, the start of the attempt that did.
+async fn wait_for_leadership(lease: &LeaseLock, clock: &dyn Clock) -> SystemTime {
+    let mut waiting = false;
+    loop {
+        let attempt = clock.now();
+        match lease.try_acquire().await {
+            Ok(true) => {
+                info!("Elected leader");
+                return attempt;
+            }
+            Ok(false) if !waiting => {
+                info!("Another instance is the leader, waiting as a standby");
+                waiting = true;
+            }
+            Ok(false) => {}
+            Err(err) => warn!("Failed to acquire the lease: {}", err)
+        }
+        sleep(lease.renew_interval()).await;
+    }
+}
 
+// Reconciles one zone on its own interval and whenever a watched resource changes, until a fatal error. With `ONCE`
+// the zone is reconciled a single time. `requests` reconcile a single host of the zone in between.
+async fn zone_loop(zone: ZoneConfig, config: Arc<Config>, cf_client: Arc<CfApi>, resources: Resources,
+                   server_state: ServerState, changed: Arc<Notify>,
+                   mut requests: Option<Receiver<ReconcileRequest>>) -> Result<(), ReconcileError> {
+    let zone_name = &zone.name;
+    let mut times = RecordTimes::default();
+    let mut request: Option<ReconcileRequest> = None;
+    let mut next_pass = Instant::now();
     loop {
-        let expected = {
+        let hostnames = {
             let resources = resources.lock().await;
-            println!("Resources: {:?}", resources.keys());
-            compute_records(resources.values().collect())
-                .into_iter()
-                .filter(|r| r.name.ends_with(&zone_name))
-                .collect()
+            hostnames_to_resolve(&resources.values().collect::<Vec<_>>())
         };
-        let expected = dedupe_records(expected);
-        println!("Expected: {:?}", expected);
-
-        if let Err(err) = async {
-            let zone = cf_client.zones().await?
-                .into_iter()
-                .find(|z| z.name == zone_name)
-                .ok_or(anyhow!("zone not found"))?;
-            let actual = cf_client.records(&zone.id).await?;
-            println!("Actual: {:?}", actual);
-
-            let plan = plan(&expected, &actual);
-            println!("Plan: {:?}", plan);
-
-            for change in plan {
-                use kube_cloudflare_dns::plan::PlanAction::*;
-
-                if let Err(err) = match change {
-                    Add(record) => cf_client.create_record(&zone.id, &record).await,
-                    Delete(record) => cf_client.delete_record(&zone.id, &record.id).await,
-                    Update(record) => cf_client.update_record(&zone.id, &record).await
-                } {
-                    println!("{}", err);
+        let resolved = resolve_hostnames(hostnames).await;
+
+        let (expected, disabled, custom) = {
+            let resources = resources.lock().await;
+            debug!("Resources: {:?}", resources.keys());
+            let resources: Vec<_> = resources.values().collect();
+            let expected = expected_records(&resources, &resolved, &config, zone_name);
+            let disabled = untouched_hostnames(&resources, &expected);
+            let custom = custom_hostnames(&resources, &config);
+            (expected, disabled, custom)
+        };
+        debug!("Expected: {:?}", expected);
+        if request.is_none() {
+            times.track(&expected, server_state.clock.now(), &config);
+            server_state.metrics.managed(zone_name, &expected);
+            if let Some(debug) = server_state.debug.lock().await.get_mut(zone_name) {
+                debug.expected = expected.clone();
+            }
+        }
+
+        let desired = Desired {
+            expected: &expected,
+            disabled: &disabled,
+            host: request.as_ref().map(|(host, _)| host.as_str()),
+        };
+        let result = match timeout(config.reconcile_timeout,
+                                   reconcile(&cf_client, &config, zone_name, &desired, &times, &server_state)).await
+            .unwrap_or(Err(ReconcileError::Timeout(config.reconcile_timeout))) {
+            Err(err) if err.is_fatal() => {
+                if let Some((_, reply)) = request.take() {
+                    let _ = reply.send(Err(err.to_string()));
+                }
+                return Err(err);
+            }
+            result => result
+        };
+        // An on-demand reconcile only answers its request, then waits like any other pass.
+        if let Some((_, reply)) = request.take() {
+            let _ = reply.send(result.map_err(|err| err.to_string()));
+        } else {
+            let applied = result.as_ref()
+                .map(|plan| plan.iter().map(|change| change.action.clone()).collect())
+                .map_err(|err| err.to_string());
+            server_state.record_history(zone_name, &applied).await;
+            if config.custom_hostnames && *zone_name == config.zones[0].name {
+                if let Err(err) = reconcile_custom_hostnames(&cf_client, zone_name, &custom, &server_state).await {
+                    warn!("custom hostnames {}: {}", zone_name, err);
+                }
+            }
+            if let Some(readiness) = server_state.readiness.lock().await.get_mut(zone_name) {
+                match &applied {
+                    Ok(_) => readiness.succeeded(&*server_state.clock),
+                    Err(err) => {
+                        error!("{}: {}", zone_name, err);
+                        readiness.failed(err);
+                    }
                 }
             }
 
-            Ok(()) as anyhow::Result<()>
-        }.await {
-            println!("{}", err)
+            // A failed `ONCE` run fails the process, so a CronJob sees it.
+            if config.once {
+                return result.map(|_| ());
+            }
+            next_pass = Instant::now() + zone.interval;
         }
+        tokio::select! {
+            _ = sleep_until(next_pass) => {}
+            _ = changed.notified() => {}
+            Some(host) = next_request(&mut requests) => request = Some(host),
+        }
+    }
+}
 
-        println!("{}", iter::repeat("=").take(64).collect::<String>());
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let config = Arc::new(Config::from_env());
+    init_logging(&config.log_format);
+    info!(version = VERSION, commit = GIT_COMMIT, config = ?config, "Starting {}", APP_NAME);
 
-        tokio::select! {
-            _ = sleep(Duration::from_secs(60)) => {}
-            _ = rx.recv() => {}
+    let metrics = Arc::new(Metrics::new());
+    let cf_client = Arc::new(CfApi::new(&config, metrics.clone()));
+    if config.list_zones {
+        list_zones(&cf_client).await;
+        return;
+    }
+    if config.export_zone {
+        export_zones(&cf_client, &config).await;
+        return;
+    }
+
+    let kube_client = connect_kube().await;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    for zone in &config.zones {
+        check_zone_access(&cf_client, &zone.name).await;
+    }
+
+    let lease = config.lease.as_ref()
+        .filter(|_| config.once || config.leader_election)
+        .map(|(namespace, name)| LeaseLock::new(kube_client.clone(), namespace, name, &config.lease_identity,
+                                                config.lease_duration, clock.clone()));
+    // Overlapping `ONCE` runs, like those of a CronJob, take turns through the lease.
+    if let (true, Some(lease)) = (config.once, &lease) {
+        let acquired = clock.now();
+        match lease.try_acquire().await {
+            Ok(true) => info!("Acquired the lease"),
+            Ok(false) => {
+                info!("The lease is held by another instance, exiting");
+                return;
+            }
+            Err(err) => panic!("failed to acquire the lease: {}", err)
+        }
+        tokio::task::spawn(hold_lease(lease.clone(), acquired, clock.clone()));
+    }
+
+    let resources = Resources::default();
+    let mut server_state = ServerState::with_clock(&config, metrics, clock.clone());
+    let mut zone_requests = HashMap::new();
+    if config.debug_reconcile {
+        let (requests, receiver) = channel(1);
+        server_state.reconcile_requests = Some(requests);
+        let mut zones = HashMap::new();
+        for zone in &config.zones {
+            let (requests, receiver) = channel(1);
+            zones.insert(zone.name.clone(), requests);
+            zone_requests.insert(zone.name.clone(), receiver);
+        }
+        tokio::task::spawn(reconcile_requests(receiver, zones, config.clone()));
+    }
+    tokio::task::spawn(serve(config.listen_addr, server_state.clone()));
+    if let Some((namespace, name)) = config.pause_configmap.clone() {
+        tokio::task::spawn(pause_watcher(kube_client.clone(), namespace, name, config.paused,
+                                         server_state.paused.clone()));
+    }
+
+    // Standbys serve metrics and health while waiting to become the leader.
+    if let (false, true, Some(lease)) = (config.once, config.leader_election, &lease) {
+        let acquired = wait_for_leadership(lease, &*clock).await;
+        server_state.set_leader(true);
+        tokio::task::spawn(hold_lease(lease.clone(), acquired, clock.clone()));
+    }
+
+    let (tx, mut rx) = channel(10);
+
+    // Every WatchedResource kind registers its watcher here.
+    let watchers: Vec<SpawnWatcher> = config.watch_resources.iter()
+        .map(|kind| match kind.as_str() {
+            "service" => spawn_watcher::<WatchedService>,
+            "ingress" => spawn_watcher::<Ingress>,
+            "ingressroute" => spawn_watcher::<IngressRoute>,
+            "node" => spawn_watcher::<Node>,
+            other => unreachable!("unknown resource kind {}", other)
+        })
+        .collect();
+    info!("Watching {}", config.watch_resources.join(", "));
+    for spawn in &watchers {
+        spawn(kube_client.clone(), resources.clone(), tx.clone());
+    }
+
+    // Wait for the initial listing of every kind before the first reconcile.
+    for _ in &watchers {
+        rx.recv().await;
+    }
+
+    let mut changed = Vec::new();
+    let mut zone_loops = Vec::new();
+    for zone in &config.zones {
+        let notify = Arc::new(Notify::new());
+        changed.push(notify.clone());
+        zone_loops.push(tokio::task::spawn(zone_loop(zone.clone(), config.clone(), cf_client.clone(),
+                                                     resources.clone(), server_state.clone(), notify,
+                                                     zone_requests.remove(&zone.name))));
+    }
+
+    tokio::task::spawn(async move {
+        while rx.recv().await.is_some() {
+            changed.iter().for_each(|notify| notify.notify_one());
+        }
+    });
+
+    if config.once {
+        let results = futures::future::join_all(zone_loops).await;
+        if let Some(lease) = &lease {
+            if let Err(err) = lease.release().await {
+                warn!("Failed to release the lease: {}", err);
+            }
+        }
+        for result in results {
+            if let Err(err) = result.unwrap() {
+                panic!("{}", err);
+            }
+        }
+        return;
+    }
+    let (result, _, _) = futures::future::select_all(zone_loops).await;
+    panic!("{}", result.unwrap().unwrap_err());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::{Body, Request, Server};
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let vars = |name: &str| vars.iter()
+            .chain(&[("ZONE_NAME", "example.com"), ("CF_TOKEN", "token"), ("HOSTNAME", "test")])
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.to_string());
+        Config::from_vars(&vars, &[]).unwrap()
+    }
+
+    type Paths = Arc<std::sync::Mutex<Vec<String>>>;
+
+    // A Cloudflare API answering every request with `respond(path)` after `delay`, recording the requested paths.
+    fn cloudflare(config: &Config, delay: Duration, respond: fn(&str) -> (u16, &'static str)) -> (CfApi, Paths) {
+        let paths = Paths::default();
+        let recorded = paths.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let (status, body) = respond(req.uri().path());
+                    recorded.lock().unwrap().push(req.uri().path().to_string());
+                    async move {
+                        sleep(delay).await;
+                        Ok::<_, hyper::Error>(hyper::Response::builder().status(status).body(Body::from(body)).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::task::spawn(server);
+        (CfApi::with_endpoint(config, Arc::new(Metrics::new()), &endpoint), paths)
+    }
+
+    fn empty_zone(path: &str) -> (u16, &'static str) {
+        match path {
+            "/zones" => (200, r#"{"success": true, "errors": [], "result": [{"id": "zone", "name": "example.com"}]}"#),
+            _ => (200, r#"{"success": true, "errors": [], "result": []}"#)
         }
     }
+
+    #[tokio::test]
+    async fn on_demand_reconcile_waits_for_the_next_pass() {
+        let config = Arc::new(config(&[]));
+        let (cf_client, paths) = cloudflare(&config, Duration::ZERO, empty_zone);
+        let server_state = ServerState::new(&config, Arc::new(Metrics::new()));
+        let (requests, receiver) = channel(1);
+        tokio::task::spawn(zone_loop(config.zones[0].clone(), config.clone(), Arc::new(cf_client),
+                                     Resources::default(), server_state, Arc::new(Notify::new()), Some(receiver)));
+
+        let (reply, replied) = tokio::sync::oneshot::channel();
+        requests.send(("app.example.com".to_string(), reply)).await.unwrap();
+        assert!(replied.await.unwrap().unwrap().is_empty());
+        sleep(Duration::from_millis(100)).await;
+        // One full pass and the on-demand one, without another full pass after answering.
+        assert_eq!(paths.lock().unwrap().len(), 4, "{:?}", paths.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn kube_connection_is_retried_with_backoff() {
+        let mut attempts = 0;
+        let mut waits = Vec::new();
+        let result = retry_connect(|| {
+            attempts += 1;
+            futures::future::ready(if attempts < 4 { Err("refused") } else { Ok(attempts) })
+        }, |backoff| {
+            waits.push(backoff.as_secs());
+            futures::future::ready(())
+        }).await;
+        assert_eq!(result, Ok(4));
+        assert_eq!(waits, vec![1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn kube_connection_gives_up() {
+        let mut waits = Vec::new();
+        let result: Result<(), _> = retry_connect(|| futures::future::ready(Err("refused")), |backoff| {
+            waits.push(backoff.as_secs());
+            futures::future::ready(())
+        }).await;
+        assert_eq!(result, Err("refused"));
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 32, 60, 60, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn stuck_reconcile_times_out() {
+        let config = Arc::new(config(&[("ONCE", "true"), ("RECONCILE_TIMEOUT_SECONDS", "1")]));
+        let (cf_client, _) = cloudflare(&config, Duration::from_secs(30), empty_zone);
+        let server_state = ServerState::new(&config, Arc::new(Metrics::new()));
+        let result = zone_loop(config.zones[0].clone(), config.clone(), Arc::new(cf_client), Resources::default(),
+                               server_state, Arc::new(Notify::new()), None).await;
+        assert!(matches!(result, Err(ReconcileError::Timeout(timeout)) if timeout == Duration::from_secs(1)),
+                "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn failed_once_run_returns_the_error() {
+        let config = Arc::new(config(&[("ONCE", "true")]));
+        let (cf_client, _) = cloudflare(&config, Duration::ZERO, |_| {
+            (400, r#"{"success": false, "errors": [{"code": 1004, "message": "bad"}]}"#)
+        });
+        let server_state = ServerState::new(&config, Arc::new(Metrics::new()));
+        let result = zone_loop(config.zones[0].clone(), config.clone(), Arc::new(cf_client), Resources::default(),
+                               server_state, Arc::new(Notify::new()), None).await;
+        assert!(matches!(result, Err(ReconcileError::Cloudflare(CfError::Api(_)))), "{:?}", result);
+    }
 }
@@ -0,0 +1,84 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+                 TextEncoder};
+
+use crate::api::Record;
+use crate::plan::{is_marker, PlanAction};
+
+pub struct Metrics {
+    registry: Registry,
+    pub records_created: IntCounter,
+    pub records_updated: IntCounter,
+    pub records_deleted: IntCounter,
+    pub plan_size: Histogram,
+    pub managed_records: IntGaugeVec,
+    pub api_duration: HistogramVec,
+    pub leader: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let records_created = IntCounter::new("dns_records_created_total", "DNS records created").unwrap();
+        let records_updated = IntCounter::new("dns_records_updated_total", "DNS records updated").unwrap();
+        let records_deleted = IntCounter::new("dns_records_deleted_total", "DNS records deleted").unwrap();
+        let plan_size = Histogram::with_opts(
+            HistogramOpts::new("dns_plan_size", "Number of changes planned per reconcile")
+                .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0])
+        ).unwrap();
+        let managed_records = IntGaugeVec::new(
+            Opts::new("dns_managed_records", "DNS records published, ownership markers counted as kind marker"),
+            &["zone", "kind"]
+        ).unwrap();
+        let api_duration = HistogramVec::new(
+            HistogramOpts::new("cloudflare_api_duration_seconds", "Duration of Cloudflare API requests"),
+            &["operation"]
+        ).unwrap();
+        let leader = IntGauge::new("dns_leader", "Whether this instance reconciles, 0 while waiting for the lease")
+            .unwrap();
+
+        registry.register(Box::new(records_created.clone())).unwrap();
+        registry.register(Box::new(records_updated.clone())).unwrap();
+        registry.register(Box::new(records_deleted.clone())).unwrap();
+        registry.register(Box::new(plan_size.clone())).unwrap();
+        registry.register(Box::new(managed_records.clone())).unwrap();
+        registry.register(Box::new(api_duration.clone())).unwrap();
+        registry.register(Box::new(leader.clone())).unwrap();
+
+        Self {
+            registry,
+            records_created,
+            records_updated,
+            records_deleted,
+            plan_size,
+            managed_records,
+            api_duration,
+            leader,
+        }
+    }
+
+    pub fn applied(&self, action: &PlanAction) {
+        match action {
+            PlanAction::Add(_) => self.records_created.inc(),
+            PlanAction::Update(_) => self.records_updated.inc(),
+            PlanAction::Delete(_) => self.records_deleted.inc(),
+        }
+    }
+
+    pub fn managed(&self, zone: &str, records: &[Record]) {
+        let markers = records.iter().filter(|r| is_marker(r)).count();
+        self.managed_records.with_label_values(&[zone, "data"]).set((records.len() - markers) as i64);
+        self.managed_records.with_label_values(&[zone, "marker"]).set(markers as i64);
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
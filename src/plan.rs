@@ -1,39 +1,118 @@
-use std::collections::hash_map::RandomState;
-use std::collections::HashSet;
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use k8s_openapi::api::core::v1::{LoadBalancerStatus, Service, ServiceSpec, ServiceStatus};
-use k8s_openapi::api::networking::v1::{Ingress, IngressSpec, IngressStatus};
+use chrono::{DateTime, SecondsFormat, Utc};
+use k8s_openapi::api::core::v1::{LoadBalancerIngress, LoadBalancerStatus, Service, ServicePort, ServiceSpec,
+                                 ServiceStatus};
+use k8s_openapi::api::networking::v1::{Ingress, IngressStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
 
-use crate::{APP_NAME, HOSTNAME_LABEL};
-use crate::api::Record;
-use crate::resource::WatchedResource;
+use crate::{APP_NAME, CAA_LABEL, CONTENT_TEMPLATE_LABEL, DISABLED_LABEL, HOSTNAME_LABEL, LB_SELECT_LABEL,
+            PROXIED_LABEL, PUBLISH_PROXY_IPS_LABEL, RECORD_TYPE_LABEL, RESOLVE_HOSTNAME_LABEL, SETTINGS_LABEL,
+            SRV_FROM_PORTS_LABEL, TTL_LABEL, USE_HOSTNAME_LABEL, USE_REQUESTED_IP_LABEL, VERSION, WEIGHTS_LABEL,
+            ZONE_LABEL};
+use crate::api::{CustomHostname, Record, RecordType};
+use crate::config::{Config, ZoneConfig};
+use crate::resolve::ResolvedHostnames;
+use crate::resource::{ResourceKey, WatchedResource};
+use crate::traefik::IngressRoute;
 
-#[derive(Debug)]
+const DEFAULT_WEIGHT: u32 = 1;
+pub const AUTO_TTL: u32 = 1;
+const MIN_TTL: u32 = 60;
+const MAX_TTL: u32 = 86400;
+const INGRESS_CLASS_ANNOTATION: &str = "kubernetes.io/ingress.class";
+const TOMBSTONE: &str = "kube-cloudflare-dns:pending-delete";
+
+#[derive(Debug, Clone)]
+pub enum Ownership {
+    Txt,
+    Comment(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub enum PlanAction {
     Add(Record),
     Delete(Record),
     Update(Record),
 }
 
-fn ingress_addresses(ingress: &Ingress) -> Vec<String> {
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedChange {
+    #[serde(flatten)]
+    pub action: PlanAction,
+    pub reason: String,
+}
+
+impl PlannedChange {
+    fn new(action: PlanAction, reason: impl Into<String>) -> Self {
+        Self { action, reason: reason.into() }
+    }
+}
+
+fn flag(meta: &ObjectMeta, label: &str) -> bool {
+    matches!(meta.annotations.as_ref().and_then(|a| a.get(label)), Some(value) if value == "true")
+}
+
+fn resolve_hostname_enabled(meta: &ObjectMeta) -> bool {
+    flag(meta, RESOLVE_HOSTNAME_LABEL)
+}
+
+fn load_balancer_addresses(ingress: &[LoadBalancerIngress], resolve: bool,
+                           resolved: &ResolvedHostnames) -> Vec<String> {
+    let mut addresses = Vec::new();
+    for ingress in ingress {
+        if let Some(ip) = &ingress.ip {
+            addresses.push(ip.clone());
+        }
+        if let (true, Some(hostname)) = (resolve, &ingress.hostname) {
+            if let Some(ips) = resolved.get(hostname) {
+                addresses.extend(ips.iter().map(|ip| ip.to_string()));
+            }
+        }
+    }
+    addresses
+}
+
+fn select_addresses(meta: &ObjectMeta, addresses: Vec<String>) -> Vec<String> {
+    let is_ip = |address: &String, v4: bool| matches!(IpAddr::from_str(address), Ok(ip) if ip.is_ipv4() == v4);
+    match meta.annotations.as_ref().and_then(|a| a.get(LB_SELECT_LABEL)).map(String::as_str) {
+        None | Some("all") => addresses,
+        Some("first") => addresses.into_iter().take(1).collect(),
+        Some("ipv4") => addresses.into_iter().filter(|address| is_ip(address, true)).collect(),
+        Some("ipv6") => addresses.into_iter().filter(|address| is_ip(address, false)).collect(),
+        Some(other) => {
+            warn!("Invalid lb-select annotation {}, publishing all addresses", other);
+            addresses
+        }
+    }
+}
+
+fn load_balancer_hostnames(ingress: &[LoadBalancerIngress]) -> Vec<String> {
+    ingress.iter()
+        .filter_map(|i| i.hostname.clone())
+        .collect()
+}
+
+fn ingress_load_balancer(ingress: &Ingress) -> Option<&Vec<LoadBalancerIngress>> {
     if let Some(IngressStatus {
                     load_balancer:
                     Some(LoadBalancerStatus {
                              ingress: Some(ingress)
                          })
                 }) = &ingress.status {
-        ingress.into_iter()
-            .filter_map(|i| i.ip.clone())
-            .collect()
+        Some(ingress)
     } else {
-        Vec::new()
+        None
     }
 }
 
-fn service_addresses(service: &Service) -> Vec<String> {
+fn service_load_balancer(service: &Service) -> Option<&Vec<LoadBalancerIngress>> {
     match service {
         Service {
             spec: Some(ServiceSpec {
@@ -47,128 +126,2579 @@ fn service_addresses(service: &Service) -> Vec<String> {
                             ingress: Some(ingress), ..
                         }), ..
                 }), ..
-        } if service_type == "LoadBalancer" => {
-            ingress.into_iter()
-                .filter_map(|ingress| ingress.ip.as_ref())
-                .cloned()
-                .collect()
+        } if service_type == "LoadBalancer" => Some(ingress),
+        _ => None
+    }
+}
+
+// With `USE_HOSTNAME_LABEL` the status hostname is published as CNAME, a status with only IPs still gets A records.
+fn ingress_addresses(ingress: &Ingress, resolved: &ResolvedHostnames) -> Vec<String> {
+    if flag(&ingress.metadata, USE_HOSTNAME_LABEL) {
+        let hostname = ingress_load_balancer(ingress).and_then(|lb| load_balancer_hostnames(lb).into_iter().next());
+        if let Some(hostname) = hostname {
+            return vec![hostname];
+        }
+    }
+
+    match ingress_load_balancer(ingress) {
+        Some(lb) => {
+            let addresses = load_balancer_addresses(lb, resolve_hostname_enabled(&ingress.metadata), resolved);
+            select_addresses(&ingress.metadata, addresses)
         }
+        None => Vec::new()
+    }
+}
+
+fn requested_ip(service: &Service) -> Option<&String> {
+    match &service.spec {
+        Some(ServiceSpec { load_balancer_ip: Some(ip), .. }) if flag(&service.metadata, USE_REQUESTED_IP_LABEL) =>
+            Some(ip).filter(|ip| IpAddr::from_str(ip).is_ok()),
+        _ => None
+    }
+}
+
+/// Drops the LoadBalancer addresses the Service reports with `ipMode: Proxy` from its status, unless it has
+/// `PUBLISH_PROXY_IPS_LABEL`.
+pub fn skip_proxy_addresses(service: &mut Service, proxy_ips: &[String]) {
+    if proxy_ips.is_empty() || flag(&service.metadata, PUBLISH_PROXY_IPS_LABEL) {
+        return;
+    }
+    let ingress = service.status.as_mut()
+        .and_then(|status| status.load_balancer.as_mut())
+        .and_then(|lb| lb.ingress.as_mut());
+    if let Some(ingress) = ingress {
+        warn!("Skip ipMode Proxy addresses {} of Service {}/{}", proxy_ips.join(", "),
+              service.metadata.namespace.as_deref().unwrap_or_default(),
+              service.metadata.name.as_deref().unwrap_or_default());
+        ingress.retain(|entry| !matches!(&entry.ip, Some(ip) if proxy_ips.contains(ip)));
+    }
+}
+
+fn service_addresses(service: &Service, resolved: &ResolvedHostnames) -> Vec<String> {
+    let lb_addresses = service_load_balancer(service)
+        .map(|lb| load_balancer_addresses(lb, resolve_hostname_enabled(&service.metadata), resolved))
+        .map(|addresses| select_addresses(&service.metadata, addresses));
+    match (lb_addresses, requested_ip(service)) {
+        (Some(addresses), _) if !addresses.is_empty() => return addresses,
+        (_, Some(ip)) => return vec![ip.clone()],
+        (Some(addresses), None) => return addresses,
+        (None, None) => {}
+    }
+
+    match service {
         Service {
             spec: Some(
                 ServiceSpec {
                     cluster_ips: Some(ips), ..
                 }), ..
-        } => ips.clone(),
+        } => ips.iter()
+            .filter(|ip| IpAddr::from_str(ip).is_ok())
+            .cloned()
+            .collect(),
         _ => vec![]
     }
 }
 
-fn records_for_hostname(hostname: &str, addresses: &[String]) -> Vec<Record> {
+fn is_node_port(service: &Service) -> bool {
+    matches!(&service.spec, Some(ServiceSpec { type_: Some(t), .. }) if t == "NodePort")
+}
+
+// With `NODEPORT_SOURCE` NodePort Services are published with the `NODE_ADDRESS_TYPE` addresses of all nodes.
+fn node_addresses(resources: &[&WatchedResource], config: &Config) -> Vec<String> {
+    let mut addresses: Vec<String> = resources.iter()
+        .filter_map(|resource| match resource {
+            WatchedResource::Node(node) => node.status.as_ref()?.addresses.as_ref(),
+            _ => None
+        })
+        .flatten()
+        .filter(|address| address.type_ == config.node_address_type)
+        .map(|address| address.address.clone())
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+// Condition types reporting readiness, others like `LoadBalancerPortsError` are true when something is wrong.
+const READINESS_CONDITIONS: &[&str] = &["Ready", "Available", "LoadBalancerReady"];
+
+fn service_not_ready(service: &Service) -> Option<&'static str> {
+    let is_load_balancer = matches!(&service.spec, Some(ServiceSpec { type_: Some(t), .. }) if t == "LoadBalancer");
+    let provisioned = matches!(service_load_balancer(service), Some(lb) if !lb.is_empty()) ||
+        requested_ip(service).is_some();
+    if is_load_balancer && !provisioned {
+        return Some("load balancer is not provisioned yet");
+    }
+
+    match &service.status {
+        Some(ServiceStatus { conditions: Some(conditions), .. }) if conditions.iter()
+            .any(|c| READINESS_CONDITIONS.contains(&c.type_.as_str()) && c.status != "True") =>
+            Some("readiness conditions are not all true"),
+        _ => None
+    }
+}
+
+fn ingress_not_ready(ingress: &Ingress) -> Option<&'static str> {
+    match ingress_load_balancer(ingress) {
+        Some(lb) if !lb.is_empty() => None,
+        _ => Some("load balancer is not provisioned yet")
+    }
+}
+
+fn not_ready(resource: &WatchedResource, config: &Config) -> Option<&'static str> {
+    if !config.require_ready {
+        return None;
+    }
+
+    match resource {
+        WatchedResource::Ingress(ingress) if config.publish_service.is_none() => ingress_not_ready(ingress),
+        WatchedResource::Service(service) => service_not_ready(service),
+        WatchedResource::Ingress(_) | WatchedResource::IngressRoute(_) | WatchedResource::Node(_) => None,
+    }
+}
+
+fn service_srv_ports(service: &Service) -> Vec<ServicePort> {
+    match &service.spec {
+        Some(ServiceSpec { ports: Some(ports), .. }) if flag(&service.metadata, SRV_FROM_PORTS_LABEL) =>
+            ports.iter()
+                .filter(|port| port.name.is_some())
+                .cloned()
+                .collect(),
+        _ => Vec::new()
+    }
+}
+
+pub fn hostnames_to_resolve(resources: &[&WatchedResource]) -> Vec<String> {
+    let mut hostnames = Vec::new();
+    for resource in resources {
+        let lb = match resource {
+            WatchedResource::Ingress(ingress) if resolve_hostname_enabled(&ingress.metadata) =>
+                ingress_load_balancer(ingress),
+            WatchedResource::Service(service) if resolve_hostname_enabled(&service.metadata) =>
+                service_load_balancer(service),
+            _ => None
+        };
+        if let Some(lb) = lb {
+            hostnames.extend(load_balancer_hostnames(lb));
+        }
+    }
+    hostnames
+}
+
+fn caa_record(hostname: &str, value: &str) -> Option<Record> {
+    let mut parts = value.trim().splitn(3, char::is_whitespace);
+    let flags: u8 = parts.next()?.parse().ok()?;
+    let tag = parts.next()?;
+    let value = parts.next()?.trim().trim_matches('"');
+
+    Some(Record {
+        _type: RecordType::CAA,
+        name: hostname.into(),
+        content: format!("{} {} \"{}\"", flags, tag, value),
+        data: Some(json!({
+            "flags": flags,
+            "tag": tag,
+            "value": value,
+        })),
+        ..Default::default()
+    })
+}
+
+fn parse_weights(hostname: &str, value: &str) -> HashMap<String, u32> {
+    let mut weights = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once('=').map(|(ip, weight)| (ip.trim(), weight.trim().parse::<u32>())) {
+            Some((ip, Ok(weight))) => {
+                weights.insert(ip.to_string(), weight);
+            }
+            _ => warn!("Invalid weights annotation entry for {}: {}", hostname, entry)
+        }
+    }
+    weights
+}
+
+fn weighted_addresses(hostname: &str, addresses: &[String],
+                      annotations: Option<&BTreeMap<String, String>>) -> Vec<String> {
+    let weights = match annotations.and_then(|a| a.get(WEIGHTS_LABEL)) {
+        Some(value) => parse_weights(hostname, value),
+        None => return addresses.to_vec()
+    };
+    let weight = |addr: &String| weights.get(addr).copied().unwrap_or(DEFAULT_WEIGHT);
+
+    let mut addresses: Vec<String> = addresses.iter()
+        .filter(|addr| weight(addr) > 0)
+        .cloned()
+        .collect();
+    addresses.sort_by(|a, b| weight(b).cmp(&weight(a)).then_with(|| a.cmp(b)));
+    addresses
+}
+
+fn parse_ttl(hostname: &str, value: &str) -> u32 {
+    let value = value.trim();
+    if value == "auto" {
+        return AUTO_TTL;
+    }
+
+    match value.parse() {
+        Ok(ttl) if ttl == AUTO_TTL || (MIN_TTL..=MAX_TTL).contains(&ttl) => ttl,
+        Ok(ttl) => {
+            warn!("TTL annotation for {} out of range {}-{}: {}, using auto", hostname, MIN_TTL, MAX_TTL, ttl);
+            AUTO_TTL
+        }
+        Err(_) => {
+            warn!("Invalid TTL annotation for {}: {}, using auto", hostname, value);
+            AUTO_TTL
+        }
+    }
+}
+
+fn srv_record(hostname: &str, port: &ServicePort) -> Option<Record> {
+    let name = port.name.as_ref()?;
+    let protocol = port.protocol.as_deref().unwrap_or("TCP").to_lowercase();
+
+    Some(Record {
+        _type: RecordType::SRV,
+        name: format!("_{}._{}.{}", name, protocol, hostname),
+        content: format!("0 {} {}", port.port, hostname),
+        data: Some(json!({
+            "priority": 0,
+            "weight": 0,
+            "port": port.port,
+            "target": hostname,
+        })),
+        ..Default::default()
+    })
+}
+
+// A hostname without a dot is taken relative to the zone when only one zone is configured, as Cloudflare
+// would store it. Reverse zones don't count, they never hold hostnames.
+fn qualified_hostname(hostname: &str, zones: &[ZoneConfig]) -> String {
+    let mut forward = zones.iter().filter(|zone| !zone.reverse);
+    match (forward.next(), forward.next()) {
+        (Some(zone), None) if !hostname.contains('.') => {
+            let qualified = format!("{}.{}", hostname, zone.name);
+            debug!("Qualified relative hostname {} as {}", hostname, qualified);
+            qualified
+        }
+        _ => hostname.trim_end_matches('.').to_string()
+    }
+}
+
+fn records_for_hostname(hostname: &str, addresses: &[String], ports: &[ServicePort],
+                        annotations: Option<&BTreeMap<String, String>>, source: &ObjectMeta,
+                        config: &Config) -> Vec<Record> {
+    let hostname = &qualified_hostname(hostname, &config.zones);
+    let addresses = weighted_addresses(hostname, addresses, annotations);
     if addresses.is_empty() {
         return Vec::new();
     }
 
+    let ttl = match annotations.and_then(|a| a.get(TTL_LABEL)) {
+        Some(value) => parse_ttl(hostname, value),
+        None => AUTO_TTL
+    };
+
+    let record_type = annotations.and_then(|a| a.get(RECORD_TYPE_LABEL))
+        .and_then(|value| match value.to_uppercase().as_str() {
+            "A" => Some(RecordType::A),
+            "AAAA" => Some(RecordType::AAAA),
+            "CNAME" => Some(RecordType::CNAME),
+            _ => {
+                warn!("Invalid record type annotation for {}, expected A, AAAA or CNAME: {}", hostname, value);
+                None
+            }
+        });
+
     let mut records = vec![];
-    for addr in addresses {
-        let _type = match IpAddr::from_str(&addr) {
-            Ok(IpAddr::V4(_)) => "A",
-            Ok(IpAddr::V6(_)) => "AAAA",
-            Err(_) => continue
-        }.to_string();
+    for addr in &addresses {
+        let _type = match IpAddr::from_str(addr) {
+            Ok(IpAddr::V4(_)) => RecordType::A,
+            Ok(IpAddr::V6(_)) => RecordType::AAAA,
+            Err(_) => RecordType::CNAME
+        };
+        if let Some(record_type) = record_type.as_ref().filter(|record_type| **record_type != _type) {
+            warn!("Skip {} for {}: not a valid {} record content", addr, hostname, record_type);
+            continue;
+        }
         records.push(Record {
             _type,
             name: hostname.into(),
             content: addr.clone(),
             id: "".into(),
+            ttl: Some(ttl),
+            ..Default::default()
         });
     }
-    records.push(Record {
-        _type: "TXT".into(),
-        name: hostname.into(),
-        content: APP_NAME.into(),
-        id: "".into(),
-    });
+    if let Some(caa) = annotations.and_then(|a| a.get(CAA_LABEL)) {
+        match caa_record(hostname, caa) {
+            Some(record) => records.push(Record { ttl: Some(ttl), ..record }),
+            None => warn!("Invalid CAA annotation for {}: {}", hostname, caa)
+        }
+    }
+    records.extend(ports.iter()
+        .filter_map(|port| srv_record(hostname, port))
+        .map(|record| Record { ttl: Some(ttl), ..record }));
+
+    if let Some(settings) = annotations.and_then(|a| a.get(SETTINGS_LABEL)) {
+        match serde_json::from_str::<Value>(settings) {
+            Ok(settings) if settings.is_object() => for record in &mut records {
+                record.settings = Some(settings.clone());
+            },
+            _ => warn!("Invalid settings annotation for {}, expected a JSON object: {}", hostname, settings)
+        }
+    }
+
+    let mut records = with_ownership(records, config);
+
+    let proxied = match annotations.and_then(|a| a.get(PROXIED_LABEL)) {
+        Some(value) => value == "true",
+        None => config.proxied_default
+    };
+    for record in &mut records {
+        record.proxied = Some(proxied && record._type.is_proxiable());
+        if record.proxied == Some(true) {
+            // Cloudflare always reports automatic TTL for proxied records
+            record.ttl = Some(AUTO_TTL);
+        }
+    }
+    if let (true, Some(version)) = (config.stamp_resource_version, &source.resource_version) {
+        stamp(&mut records, version);
+    }
     records
 }
 
-pub fn compute_records(resources: Vec<&WatchedResource>) -> Vec<Record> {
-    let mut records = Vec::new();
-    for resource in resources {
-        match resource {
-            WatchedResource::Ingress(ingress) => {
-                if let Some(IngressSpec {
-                                rules: Some(rules), ..
-                            }) = &ingress.spec {
-                    for rule in rules {
-                        records.extend(records_for_hostname(rule.host.as_ref().unwrap(),
-                                                            &ingress_addresses(ingress)));
-                    }
-                }
-            }
-            WatchedResource::Service(service) => {
-                if let Some(annotations) = &service.metadata.annotations {
-                    if let Some(hostname) = annotations.get(HOSTNAME_LABEL) {
-                        records.extend(records_for_hostname(hostname, &service_addresses(service)));
+// With `STAMP_RESOURCE_VERSION` records note the resourceVersion of their source in the comment. Comments are
+// never compared, so the stamp is written by creates and updates only and never causes one.
+fn stamp(records: &mut [Record], version: &str) {
+    for record in records.iter_mut().filter(|record| !is_marker(record)) {
+        record.comment = Some(match &record.comment {
+            Some(comment) => format!("{} resourceVersion={}", comment, version),
+            None => format!("resourceVersion={}", version)
+        });
+    }
+}
+
+fn with_ownership(mut records: Vec<Record>, config: &Config) -> Vec<Record> {
+    match &config.ownership {
+        Ownership::Txt => {
+            // Cloudflare rejects a marker at the name of a CNAME.
+            if config.txt_marker_prefix.is_none() {
+                records.retain(|r| {
+                    if r._type == RecordType::CNAME {
+                        warn!("Skip CNAME {}: its TXT marker can't share the name, set TXT_MARKER_PREFIX or \
+                               OWNERSHIP=comment", r.name);
                     }
-                }
+                    r._type != RecordType::CNAME
+                });
             }
+            let mut names: Vec<String> = records.iter().map(|r| r.name.clone()).collect();
+            names.sort();
+            names.dedup();
+            records.extend(names.into_iter().map(|name| Record {
+                _type: RecordType::TXT,
+                name: marker_name(&name, config),
+                content: new_marker_content(config),
+                id: "".into(),
+                ttl: Some(AUTO_TTL),
+                ..Default::default()
+            }));
+        }
+        Ownership::Comment(prefix) => for record in &mut records {
+            record.comment = Some(format!("{}{}", prefix, APP_NAME));
         }
     }
     records
 }
 
-pub fn dedupe_records(records: Vec<Record>) -> Vec<Record> {
-    Vec::from_iter(HashSet::<Record, RandomState>::from_iter(records))
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = ip.octets().iter().rev()
+                .flat_map(|octet| vec![octet & 0xf, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+// PTR records pointing the published addresses back to their hostnames, for addresses within `REVERSE_ZONES`.
+fn ptr_records(records: &[Record], config: &Config) -> Vec<Record> {
+    let ptrs = records.iter()
+        .filter(|r| matches!(r._type, RecordType::A | RecordType::AAAA) && !r.name.starts_with("*."))
+        .filter_map(|r| Some((reverse_name(IpAddr::from_str(&r.content).ok()?), r)))
+        .filter(|(name, _)| config.reverse_zones.iter().any(|zone| in_zone(name, zone)))
+        .map(|(name, r)| Record {
+            _type: RecordType::PTR,
+            name,
+            content: r.name.clone(),
+            ttl: r.ttl,
+            proxied: Some(false),
+            ..Default::default()
+        })
+        .collect();
+    with_ownership(ptrs, config).into_iter()
+        .map(|r| Record { proxied: Some(false), ..r })
+        .collect()
+}
+
+fn ingress_class(ingress: &Ingress) -> Option<&String> {
+    ingress.spec.as_ref()
+        .and_then(|spec| spec.ingress_class_name.as_ref())
+        .or_else(|| ingress.metadata.annotations.as_ref()
+            .and_then(|a| a.get(INGRESS_CLASS_ANNOTATION)))
+}
+
+fn ingress_class_matches(ingress: &Ingress, config: &Config) -> bool {
+    config.ingress_classes.is_empty() ||
+        matches!(ingress_class(ingress), Some(class) if config.ingress_classes.contains(class))
+}
+
+fn disabled(resource: &WatchedResource) -> bool {
+    flag(resource.metadata(), DISABLED_LABEL)
 }
 
-pub fn plan(expected: &[Record], actual: &[Record]) -> Vec<PlanAction> {
-    fn find<'a>(records: &'a [Record], record: &Record) -> Option<&'a Record> {
-        records.into_iter()
-            .find(|r| r._type == record._type &&
-                r.name == record.name)
+// The zone annotation pins the records of a resource to one of the zones containing them, e.g. the parent zone
+// of a delegated subdomain zone.
+fn pin_zone(resource: &WatchedResource, records: &mut [Record], config: &Config) {
+    let zone = match resource.metadata().annotations.as_ref().and_then(|a| a.get(ZONE_LABEL)) {
+        Some(zone) => zone,
+        None => return
+    };
+    if !config.zones.iter().any(|z| z.name == *zone) {
+        warn!("Ignoring zone annotation of {}: {} is not in ZONE_NAME", resource.key(), zone);
+        return;
+    }
+    for record in records {
+        if in_zone(&record.name, zone) {
+            record.zone = Some(zone.clone());
+        } else {
+            warn!("Ignoring zone annotation of {} for {}: not within {}", resource.key(), record.name, zone);
+        }
     }
+}
 
-    let managed: HashSet<String> = actual.iter()
-        .filter(|r| r._type == "TXT" && r.content == APP_NAME)
-        .map(|r| r.name.clone())
-        .collect();
-    let not_managed: HashSet<String> = actual.iter()
-        .filter(|r| !managed.contains(&r.name))
-        .map(|r| r.name.clone())
+// The rule hosts, with `INCLUDE_TLS_HOSTS` followed by the TLS hosts not among them.
+fn ingress_hosts(ingress: &Ingress, include_tls: bool) -> Vec<String> {
+    let mut hosts: Vec<String> = ingress.spec.iter()
+        .flat_map(|spec| spec.rules.iter().flatten())
+        .filter_map(|rule| rule.host.clone())
         .collect();
+    if include_tls {
+        let tls_hosts = ingress.spec.iter()
+            .flat_map(|spec| spec.tls.iter().flatten())
+            .flat_map(|tls| tls.hosts.iter().flatten());
+        for host in tls_hosts {
+            if !hosts.contains(host) {
+                hosts.push(host.clone());
+            }
+        }
+    }
+    hosts
+}
 
-    let mut plan = Vec::new();
+fn resource_hostnames(resource: &WatchedResource) -> Vec<String> {
+    match resource {
+        WatchedResource::Ingress(ingress) => ingress_hosts(ingress, true),
+        WatchedResource::Service(service) => service.metadata.annotations.iter()
+            .filter_map(|annotations| annotations.get(HOSTNAME_LABEL).cloned())
+            .collect(),
+        WatchedResource::IngressRoute(route) => route.hosts(),
+        WatchedResource::Node(_) => Vec::new()
+    }
+}
 
-    for record in expected {
-        if let Some(existing) = find(actual, record) {
-            if !managed.contains(&record.name) {
-                println!("Skip updating record {} {} not managed by us", &record._type, &record.name);
-                continue;
+/// Hosts of Ingresses outside all configured zones, onboarded as custom hostnames of the first zone with
+/// `CUSTOM_HOSTNAMES`.
+pub fn custom_hostnames(resources: &[&WatchedResource], config: &Config) -> BTreeSet<String> {
+    resources.iter()
+        .filter(|resource| !disabled(resource) && not_ready(resource, config).is_none())
+        .flat_map(|resource| match resource {
+            WatchedResource::Ingress(ingress) if ingress_class_matches(ingress, config) =>
+                ingress_hosts(ingress, config.include_tls_hosts),
+            _ => Vec::new()
+        })
+        .map(|host| canonical_name(&host))
+        .filter(|host| host.contains('.') && zone_for(host, &config.zones).is_none() && valid_hostname(host))
+        .collect()
+}
+
+/// The expected custom hostnames missing from the zone. Custom hostnames are never deleted, the customer may still
+/// depend on one that was onboarded by hand.
+pub fn missing_custom_hostnames(expected: &BTreeSet<String>, actual: &[CustomHostname]) -> Vec<String> {
+    let actual: HashSet<String> = actual.iter().map(|h| canonical_name(&h.hostname)).collect();
+    expected.iter()
+        .filter(|hostname| !actual.contains(*hostname))
+        .cloned()
+        .collect()
+}
+
+pub fn disabled_hostnames(resources: &[&WatchedResource]) -> Vec<String> {
+    resources.iter()
+        .filter(|resource| disabled(resource))
+        .flat_map(|resource| resource_hostnames(resource))
+        .collect()
+}
+
+/// Whether the record was published for one of the hostnames, including its `_service._proto` SRV records and the
+/// markers of both. Markers of other names below a hostname, like all markers of the zone below the apex, are not.
+pub fn for_hostnames(record: &Record, hostnames: &[String], config: &Config) -> bool {
+    let name = canonical_name(owned_name(record, config));
+    hostnames.iter()
+        .map(|hostname| canonical_name(hostname))
+        .any(|hostname| name == hostname || srv_name_of(&name, &hostname))
+}
+
+fn srv_name_of(name: &str, hostname: &str) -> bool {
+    let mut labels = name.splitn(3, '.');
+    match (labels.next(), labels.next(), labels.next()) {
+        (Some(service), Some(proto), Some(rest)) =>
+            service.starts_with('_') && proto.starts_with('_') && rest == hostname,
+        _ => false
+    }
+}
+
+// The watched Service named `namespace/name`.
+fn find_service<'a>(resources: &[&'a WatchedResource], service: &str) -> Option<&'a Service> {
+    let (namespace, name) = service.split_once('/')?;
+    resources.iter().find_map(|resource| match resource {
+        WatchedResource::Service(service) if service.metadata.namespace.as_deref() == Some(namespace) &&
+            service.metadata.name.as_deref() == Some(name) => Some(service),
+        _ => None
+    })
+}
+
+fn ingressroute_records(route: &IngressRoute, service: Option<&Service>, resolved: &ResolvedHostnames,
+                        config: &Config) -> Vec<Record> {
+    let service = match service {
+        Some(service) => service,
+        None => {
+            debug!("Skip {}: service {} not found", ResourceKey::from(route), config.ingressroute_service);
+            return Vec::new();
+        }
+    };
+    let addresses = templated_addresses(&route.metadata, service_addresses(service, resolved));
+    if addresses.is_empty() {
+        waiting_for_addresses(&ResourceKey::from(route), &route.hosts());
+    }
+    route.hosts().iter()
+        .flat_map(|host| {
+            let annotations = host_annotations(route.metadata.annotations.as_ref(), host);
+            records_for_hostname(host, &addresses, &[], annotations.as_ref(), &route.metadata, config)
+        })
+        .collect()
+}
+
+const HOST_LABELS: [&str; 6] = [TTL_LABEL, PROXIED_LABEL, CAA_LABEL, WEIGHTS_LABEL, SETTINGS_LABEL,
+    RECORD_TYPE_LABEL];
+
+// Resources with several hosts can override the record annotations per host as `<annotation>.<host>`, e.g.
+// `kube-cloudflare-dns.github.com/proxied.api.example.com`.
+fn host_annotations(annotations: Option<&BTreeMap<String, String>>, host: &str) -> Option<BTreeMap<String, String>> {
+    let mut merged = annotations?.clone();
+    for label in &HOST_LABELS {
+        if let Some(value) = merged.get(&format!("{}.{}", label, host)).cloned() {
+            merged.insert(label.to_string(), value);
+        }
+    }
+    Some(merged)
+}
+
+// With `AUTO_HOSTNAME_TEMPLATE` LoadBalancer Services without a hostname annotation are published under the
+// template with `{name}` and `{namespace}` substituted.
+fn auto_hostname(service: &Service, config: &Config) -> Option<String> {
+    let template = config.auto_hostname_template.as_ref()?;
+    if !matches!(&service.spec, Some(ServiceSpec { type_: Some(t), .. }) if t == "LoadBalancer") {
+        return None;
+    }
+
+    Some(template
+        .replace("{name}", service.metadata.name.as_deref().unwrap_or_default())
+        .replace("{namespace}", service.metadata.namespace.as_deref().unwrap_or_default()))
+}
+
+pub fn valid_hostname(name: &str) -> bool {
+    name.len() <= 253 && name.split('.').all(|label| !label.is_empty() && label.len() <= 63 &&
+        !label.starts_with('-') && !label.ends_with('-') &&
+        label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+/// Like `valid_hostname`, but also allows the underscores of labels like `_acme-challenge`, which name records
+/// other than addresses.
+pub fn valid_record_name(name: &str) -> bool {
+    name.len() <= 253 && name.split('.').all(|label| !label.is_empty() && label.len() <= 63 &&
+        !label.starts_with('-') && !label.ends_with('-') &&
+        label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+}
+
+/// Why records can't be published for the hostname, if it isn't a valid hostname within one of the zones.
+pub fn hostname_problem(hostname: &str, zones: &[ZoneConfig]) -> Option<String> {
+    let qualified = qualified_hostname(hostname, zones);
+    if !valid_hostname(qualified.strip_prefix("*.").unwrap_or(&qualified)) {
+        Some(format!("{} is not a valid hostname", hostname))
+    } else if !matches!(zone_for(&qualified, zones), Some(zone) if !zone.reverse) {
+        let zones: Vec<&str> = zones.iter()
+            .filter(|zone| !zone.reverse)
+            .map(|zone| zone.name.as_str())
+            .collect();
+        Some(format!("{} is not within the zones {}", hostname, zones.join(", ")))
+    } else {
+        None
+    }
+}
+
+fn render_template(template: &str, meta: &ObjectMeta, addresses: &[String]) -> Option<String> {
+    let ip = addresses.iter().find(|address| IpAddr::from_str(address).is_ok());
+    if template.contains("${ip}") && ip.is_none() {
+        return None;
+    }
+
+    let content = template
+        .replace("${name}", meta.name.as_deref().unwrap_or_default())
+        .replace("${namespace}", meta.namespace.as_deref().unwrap_or_default())
+        .replace("${ip}", ip.map(String::as_str).unwrap_or_default());
+    Some(content).filter(|content| IpAddr::from_str(content).is_ok() || valid_hostname(content))
+}
+
+fn templated_addresses(meta: &ObjectMeta, addresses: Vec<String>) -> Vec<String> {
+    let template = match meta.annotations.as_ref().and_then(|a| a.get(CONTENT_TEMPLATE_LABEL)) {
+        Some(template) => template,
+        None => return addresses
+    };
+    match render_template(template, meta, &addresses) {
+        Some(content) => vec![content],
+        None => {
+            warn!("Content template {} of {}/{} does not render to an IP or hostname, skipping", template,
+                  meta.namespace.as_deref().unwrap_or_default(), meta.name.as_deref().unwrap_or_default());
+            Vec::new()
+        }
+    }
+}
+
+// Resources publishing different contents for the same name would make its records flap between them, so an
+// explicit hostname wins over a derived one and then the resource lowest by namespace/name keeps the name.
+fn resolve_conflicts(mut published: Vec<(ResourceKey, bool, Vec<Record>)>) -> Vec<(Record, ResourceKey)> {
+    fn contents(records: &[Record], name: &str) -> BTreeSet<String> {
+        records.iter()
+            .filter(|r| r.name == name && !is_marker(r))
+            .map(|r| format!("{} {} {}", r._type, r.content, r.data.as_ref().map(Value::to_string).unwrap_or_default()))
+            .collect()
+    }
+
+    published.sort_by(|(a, a_derived, _), (b, b_derived, _)|
+        (a_derived, &a.namespace, &a.name, &a.kind).cmp(&(b_derived, &b.namespace, &b.name, &b.kind)));
+    let mut winners: HashMap<&str, usize> = HashMap::new();
+    let mut losers: HashSet<(usize, String)> = HashSet::new();
+    for (i, (key, _, records)) in published.iter().enumerate() {
+        for record in records.iter().filter(|r| !is_marker(r)) {
+            let winner = *winners.entry(&record.name).or_insert(i);
+            if winner != i && !losers.contains(&(i, record.name.clone())) &&
+                contents(&published[winner].2, &record.name) != contents(records, &record.name) {
+                warn!("{} and {} publish conflicting records for {}, keeping those of {}",
+                      published[winner].0, key, record.name, published[winner].0);
+                losers.insert((i, record.name.clone()));
             }
+        }
+    }
 
-            if record.content != existing.content {
-                plan.push(PlanAction::Update(
-                    Record {
-                        id: existing.id.clone(),
-                        ..record.clone()
+    published.into_iter()
+        .enumerate()
+        .flat_map(|(i, (key, _, records))| records.into_iter().map(move |record| (i, record, key.clone())))
+        .filter(|(i, record, _)| !losers.contains(&(*i, record.name.clone())))
+        .map(|(_, record, key)| (record, key))
+        .collect()
+}
+
+// A CNAME can't share the zone apex with other records, when both are published at the apex only the
+// `APEX_PREFER` type remains. Cloudflare flattens apex CNAMEs anyway, so A/AAAA records are preferred by default.
+fn resolve_apex(records: Vec<(Record, ResourceKey)>, config: &Config) -> Vec<(Record, ResourceKey)> {
+    let is_address = |record: &Record| matches!(record._type, RecordType::A | RecordType::AAAA);
+    let conflicting: HashSet<String> = config.zones.iter()
+        .map(|zone| &zone.name)
+        .filter(|apex| records.iter().any(|(r, _)| r.name == **apex && r._type == RecordType::CNAME) &&
+            records.iter().any(|(r, _)| r.name == **apex && is_address(r)))
+        .cloned()
+        .collect();
+    records.into_iter()
+        .filter(|(record, key)| {
+            let dropped = conflicting.contains(&record.name) && match config.apex_prefer {
+                RecordType::CNAME => is_address(record),
+                _ => record._type == RecordType::CNAME
+            };
+            if dropped {
+                warn!("Dropping {} {} of {}: a CNAME can't coexist with other records at the zone apex, \
+                       APEX_PREFER={}", record._type, record.name, key, config.apex_prefer);
+            }
+            !dropped
+        })
+        .collect()
+}
+
+// A resource without addresses, like a LoadBalancer Service still waiting for its IP, publishes nothing. This is
+// logged so it can be told apart from a resource that isn't configured for DNS.
+fn waiting_for_addresses(key: &ResourceKey, hostnames: &[String]) {
+    for hostname in hostnames {
+        info!("Skip {}: hostname {} has no addresses yet", key, hostname);
+    }
+}
+
+pub fn compute_records(resources: Vec<&WatchedResource>, resolved: &ResolvedHostnames,
+                       config: &Config) -> Vec<Record> {
+    compute_sourced_records(resources, resolved, config).into_iter()
+        .map(|(record, _)| record)
+        .collect()
+}
+
+/// The records the resources publish, each with the resource it's published for. PTR records belong to the
+/// resource of their A/AAAA record.
+pub fn compute_sourced_records(resources: Vec<&WatchedResource>, resolved: &ResolvedHostnames,
+                               config: &Config) -> Vec<(Record, ResourceKey)> {
+    // IngressRoutes are published with the addresses of the Service exposing Traefik, `INGRESSROUTE_SERVICE`, and
+    // Ingresses without status addresses with those of the ingress controller's `PUBLISH_SERVICE`.
+    let traefik = find_service(&resources, &config.ingressroute_service);
+    let publish_service = config.publish_service.as_ref().and_then(|service| find_service(&resources, service));
+    let node_addresses = node_addresses(&resources, config);
+    // `WILDCARD_HOSTNAME` points at the ingress controller's Service, `WILDCARD_SERVICE` or `PUBLISH_SERVICE`.
+    let wildcard_service = config.wildcard_service.as_ref().or(config.publish_service.as_ref());
+    let wildcard_source = wildcard_service.and_then(|service| find_service(&resources, service));
+    let mut published = Vec::new();
+    for resource in resources {
+        if disabled(resource) {
+            debug!("Skip {}: disabled", resource.key());
+            continue;
+        }
+        if let Some(reason) = not_ready(resource, config) {
+            debug!("Skip {}: {}", resource.key(), reason);
+            continue;
+        }
+
+        let mut records = Vec::new();
+        let mut derived = false;
+        match resource {
+            WatchedResource::Ingress(ingress) if ingress_class_matches(ingress, config) => {
+                let hosts = ingress_hosts(ingress, config.include_tls_hosts);
+                if !hosts.is_empty() {
+                    let mut addresses = ingress_addresses(ingress, resolved);
+                    if let (true, Some(service)) = (addresses.is_empty(), publish_service) {
+                        addresses = service_addresses(service, resolved);
+                    }
+                    let addresses = templated_addresses(&ingress.metadata, addresses);
+                    if addresses.is_empty() {
+                        waiting_for_addresses(&resource.key(), &hosts);
                     }
-                ));
+                    for host in &hosts {
+                        let annotations = host_annotations(ingress.metadata.annotations.as_ref(), host);
+                        records.extend(records_for_hostname(host, &addresses, &[], annotations.as_ref(),
+                                                            &ingress.metadata, config));
+                    }
+                }
             }
-        } else {
-            if not_managed.contains(&record.name) {
-                println!("Skip creating record {} {} not managed by us", &record._type, &record.name);
-                continue;
+            WatchedResource::Service(service) => {
+                let annotations = service.metadata.annotations.as_ref();
+                let hostname = match annotations.and_then(|a| a.get(HOSTNAME_LABEL)) {
+                    Some(hostname) => Some(hostname.clone()),
+                    None => {
+                        derived = true;
+                        auto_hostname(service, config)
+                    }
+                };
+                if let Some(hostname) = hostname {
+                    let addresses = if config.nodeport_source && is_node_port(service) {
+                        node_addresses.clone()
+                    } else {
+                        service_addresses(service, resolved)
+                    };
+                    let addresses = templated_addresses(&service.metadata, addresses);
+                    if addresses.is_empty() {
+                        waiting_for_addresses(&resource.key(), std::slice::from_ref(&hostname));
+                    }
+                    records.extend(records_for_hostname(&hostname, &addresses, &service_srv_ports(service),
+                                                        annotations, &service.metadata, config));
+                }
             }
+            WatchedResource::IngressRoute(route) =>
+                records.extend(ingressroute_records(route, traefik, resolved, config)),
+            WatchedResource::Ingress(_) | WatchedResource::Node(_) => {}
+        }
+        pin_zone(resource, &mut records, config);
+        published.push((resource.key(), derived, records));
+    }
 
-            plan.push(PlanAction::Add(record.clone()));
+    // The wildcard gives way to hostnames of individual resources.
+    if let (Some(hostname), Some(name)) = (&config.wildcard_hostname, wildcard_service) {
+        match wildcard_source {
+            Some(service) => {
+                let addresses = service_addresses(service, resolved);
+                published.push((ResourceKey::from(service), true,
+                                records_for_hostname(hostname, &addresses, &[], None, &service.metadata, config)));
+            }
+            None => debug!("Skip wildcard {}: service {} not found", hostname, name)
         }
     }
 
-    for record in actual {
-        if managed.contains(&record.name) && find(expected, record).is_none() {
-            plan.push(PlanAction::Delete(record.clone()))
+    let mut records = resolve_apex(resolve_conflicts(published), config);
+    let ptrs: Vec<(Record, ResourceKey)> = records.iter()
+        .flat_map(|(record, key)| ptr_records(std::slice::from_ref(record), config).into_iter()
+            .map(move |ptr| (ptr, key.clone())))
+        .collect();
+    records.extend(ptrs);
+    records
+}
+
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// The most specific of the zones containing the name, so a delegated subdomain zone wins over its parent.
+pub fn zone_for<'a>(name: &str, zones: &'a [ZoneConfig]) -> Option<&'a ZoneConfig> {
+    zones.iter()
+        .filter(|zone| in_zone(name, &zone.name))
+        .max_by_key(|zone| zone.name.len())
+}
+
+pub fn dedupe_records(records: Vec<Record>) -> Vec<Record> {
+    let mut seen = HashSet::new();
+    records.into_iter()
+        .filter(|r| seen.insert(match is_marker(r) {
+            true => Record { content: marker_identity(&r.content), ..r.clone() },
+            false => r.clone()
+        }))
+        .collect()
+}
+
+pub fn marker_content(config: &Config) -> String {
+    match &config.owner_id {
+        Some(owner_id) => format!("{},owner={}", APP_NAME, owner_id),
+        None => APP_NAME.into()
+    }
+}
+
+// New markers get the `TXT_MARKER_FIELDS` details appended, e.g. `kube-cloudflare-dns,owner=a,version=0.1.0`.
+// The creation time is added by `stamp_markers` once the marker is actually written.
+fn new_marker_content(config: &Config) -> String {
+    let mut content = marker_content(config);
+    if config.txt_marker_fields.iter().any(|field| field == "version") {
+        content.push_str(&format!(",version={}", VERSION));
+    }
+    content
+}
+
+/// Adds the `created` marker field to the markers the plan writes, unless a marker carries it already.
+pub fn stamp_markers(plan: &mut [PlannedChange], now: SystemTime, config: &Config) {
+    if !config.txt_marker_fields.iter().any(|field| field == "created") {
+        return;
+    }
+    let created = DateTime::<Utc>::from(now).to_rfc3339_opts(SecondsFormat::Secs, true);
+    for change in plan {
+        if let PlanAction::Add(record) | PlanAction::Update(record) = &mut change.action {
+            if is_marker(record) && !record.content.split(',').any(|field| field.starts_with("created=")) {
+                record.content.push_str(&format!(",created={}", created));
+            }
         }
     }
+}
 
-    plan
+// The stable part of a marker deciding ownership, the details after it never cause an update.
+fn marker_identity(content: &str) -> String {
+    content.split(',')
+        .enumerate()
+        .filter(|(i, field)| *i == 0 || field.starts_with("owner="))
+        .map(|(_, field)| field)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// With `TXT_MARKER_PREFIX` the marker of a name lives at `<prefix>.<name>` instead of next to its records.
+fn marker_name(name: &str, config: &Config) -> String {
+    match &config.txt_marker_prefix {
+        Some(prefix) => format!("{}.{}", prefix, name),
+        None => name.into()
+    }
+}
+
+// The name whose ownership the record decides or depends on.
+fn owned_name<'a>(record: &'a Record, config: &Config) -> &'a str {
+    match &config.txt_marker_prefix {
+        Some(prefix) if is_marker(record) => record.name.strip_prefix(prefix.as_str())
+            .and_then(|name| name.strip_prefix('.'))
+            .unwrap_or(&record.name),
+        _ => &record.name
+    }
+}
+
+pub fn is_marker(record: &Record) -> bool {
+    record._type == RecordType::TXT &&
+        (record.content == APP_NAME || record.content.starts_with(&format!("{},", APP_NAME)))
+}
+
+// Cloudflare reports object fields we don't set, only the expected ones are compared.
+fn object_differs(expected: &Option<Value>, actual: &Option<Value>) -> bool {
+    match (expected, actual) {
+        (Some(Value::Object(expected)), Some(Value::Object(actual))) =>
+            expected.iter().any(|(key, value)| actual.get(key) != Some(value)),
+        (expected, actual) => expected != actual
+    }
+}
+
+fn differs(record: &Record, existing: &Record) -> bool {
+    let content_differs = match record._type {
+        RecordType::CAA | RecordType::SRV => object_differs(&record.data, &existing.data),
+        RecordType::TXT if is_marker(record) && is_marker(existing) =>
+            marker_identity(&record.content) != marker_identity(&existing.content),
+        _ => record.content != existing.content
+    };
+    content_differs || (record.ttl.is_some() && record.ttl != existing.ttl) ||
+        (record.proxied.is_some() && record.proxied != existing.proxied) ||
+        (record.settings.is_some() && object_differs(&record.settings, &existing.settings))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() ||
+            ip.is_broadcast() || ip.is_documentation()),
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified() ||
+            (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80)
+    }
+}
+
+fn proxiable(record: &Record, actual: &[Record]) -> bool {
+    let existing = actual.iter()
+        .find(|r| r._type == record._type && r.name == record.name && r.content == record.content);
+    if let Some(Record { proxiable: Some(proxiable), .. }) = existing {
+        return *proxiable;
+    }
+
+    match record._type {
+        RecordType::A | RecordType::AAAA => matches!(IpAddr::from_str(&record.content), Ok(ip) if is_public(ip)),
+        _ => record._type.is_proxiable()
+    }
+}
+
+fn downgrade_unproxiable(record: &Record, actual: &[Record]) -> Record {
+    if record.proxied == Some(true) && !proxiable(record, actual) {
+        warn!("Record {} {} {} is not proxiable, publishing it unproxied",
+                 &record._type, &record.name, &record.content);
+        return Record {
+            proxied: Some(false),
+            ..record.clone()
+        };
+    }
+    record.clone()
+}
+
+// Records created before `MANAGE_SINCE` predate the controller and are never deleted.
+fn predates_management(record: &Record, config: &Config) -> bool {
+    match (&config.manage_since, &record.created_on) {
+        (Some(since), Some(created_on)) =>
+            matches!(DateTime::parse_from_rfc3339(created_on), Ok(created_on) if created_on < *since),
+        _ => false
+    }
+}
+
+// Cloudflare may report a wildcard label escaped zone file style or a name in another case than it was created
+// with, so names of both sides are compared in one form.
+fn canonical_name(name: &str) -> String {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    match name.strip_prefix("\\052.") {
+        Some(rest) => format!("*.{}", rest),
+        None => name
+    }
+}
+
+// Cloudflare may report TXT content quoted, as in zone files.
+fn canonical_content(record: &Record) -> String {
+    if record._type != RecordType::TXT {
+        return record.content.clone();
+    }
+    let content = record.content.trim();
+    content.strip_prefix('"')
+        .and_then(|content| content.strip_suffix('"'))
+        .unwrap_or(content)
+        .trim()
+        .to_string()
+}
+
+fn canonical(record: &Record) -> Record {
+    Record {
+        name: canonical_name(&record.name),
+        content: canonical_content(record),
+        ..record.clone()
+    }
+}
+
+// Records Cloudflare manages for its own products can't be changed.
+fn locked(record: &Record) -> bool {
+    record.locked == Some(true)
+}
+
+fn tombstoned(record: &Record) -> bool {
+    matches!(&record.comment, Some(comment) if comment.ends_with(TOMBSTONE))
+}
+
+// With `SOFT_DELETE` a stale record is first marked in its comment and only deleted by a later reconcile that
+// still finds it stale.
+fn delete(record: &Record, config: &Config) -> PlannedChange {
+    if !config.soft_delete {
+        return PlannedChange::new(PlanAction::Delete(record.clone()), "no longer expected");
+    }
+    if tombstoned(record) {
+        return PlannedChange::new(PlanAction::Delete(record.clone()), "still not expected after being marked");
+    }
+
+    PlannedChange::new(PlanAction::Update(Record {
+        comment: Some(match &record.comment {
+            Some(comment) => format!("{} {}", comment, TOMBSTONE),
+            None => TOMBSTONE.into()
+        }),
+        ..record.clone()
+    }), "no longer expected, marking it for deletion")
+}
+
+fn update_reason(record: &Record, existing: &Record) -> String {
+    let mut changes = Vec::new();
+    let same_marker = is_marker(record) && is_marker(existing) &&
+        marker_identity(&record.content) == marker_identity(&existing.content);
+    match record._type {
+        RecordType::CAA | RecordType::SRV => if object_differs(&record.data, &existing.data) {
+            changes.push("data changed".to_string());
+        }
+        _ => if record.content != existing.content && !same_marker {
+            changes.push(format!("content changed from {} to {}", existing.content, record.content));
+        }
+    }
+    if record.ttl.is_some() && record.ttl != existing.ttl {
+        changes.push(format!("ttl changed from {} to {}", existing.ttl.unwrap_or_default(),
+                             record.ttl.unwrap_or_default()));
+    }
+    if record.proxied.is_some() && record.proxied != existing.proxied {
+        changes.push(format!("proxied changed to {}", record.proxied.unwrap_or_default()));
+    }
+    if record.settings.is_some() && object_differs(&record.settings, &existing.settings) {
+        changes.push("settings changed".to_string());
+    }
+    if changes.is_empty() && tombstoned(existing) {
+        changes.push("expected again, removing the deletion mark".to_string());
+    }
+    changes.join(", ")
+}
+
+pub fn plan(expected: &[Record], actual: &[Record], config: &Config) -> Vec<PlanAction> {
+    planned_changes(expected, actual, config).into_iter()
+        .map(|change| change.action)
+        .collect()
+}
+
+/// The plan with the reason for each change, for the debug state.
+pub fn planned_changes(expected: &[Record], actual: &[Record], config: &Config) -> Vec<PlannedChange> {
+    fn same(a: &Record, b: &Record) -> bool {
+        a._type == b._type && a.name == b.name && is_marker(a) == is_marker(b)
+    }
+
+    // A tombstoned record that is expected again gets its tombstone removed by an update.
+    fn exact(existing: &Record, record: &Record) -> bool {
+        same(existing, record) && !differs(record, existing) && !tombstoned(existing) && !tombstoned(record)
+    }
+
+    let actual: Vec<Record> = actual.iter().map(canonical).collect();
+    let actual = &actual;
+    let marker = marker_content(config);
+    // A bare marker belongs to every owner alike, so only `MIGRATE_BARE_MARKERS` lets an owner take it over.
+    let is_ours = |record: &Record| {
+        let identity = marker_identity(&record.content);
+        identity == marker || (config.migrate_bare_markers && identity == APP_NAME)
+    };
+    // Markers of other owners sharing a name with us are left to their owner.
+    let is_foreign = |record: &Record| is_marker(record) && !is_ours(record);
+    let managed: HashSet<&str> = actual.iter()
+        .filter(|r| is_marker(r) && is_ours(r))
+        .map(|r| owned_name(r, config))
+        .collect();
+    let is_managed = |record: &Record| match &config.ownership {
+        Ownership::Txt => managed.contains(owned_name(record, config)),
+        Ownership::Comment(prefix) => matches!(&record.comment, Some(comment) if comment.starts_with(prefix))
+    };
+    // We publish no TXT records besides markers, so other TXT records, like SPF, coexist with ours.
+    let is_user_txt = |record: &Record| record._type == RecordType::TXT && !is_marker(record);
+    let not_managed: HashSet<&str> = actual.iter()
+        .filter(|r| !is_managed(r) && !is_user_txt(r))
+        .map(|r| owned_name(r, config))
+        .collect();
+
+    let expected: Vec<Record> = expected.iter()
+        .map(|r| downgrade_unproxiable(&canonical(r), actual))
+        .collect();
+
+    // With `ADOPT` a name whose records all match the expected ones is taken over instead of left alone.
+    let adoptable = |name: &str| config.adopt && actual.iter()
+        .filter(|r| owned_name(r, config) == name && !is_user_txt(r))
+        .all(|r| !is_marker(r) && expected.iter().any(|e| exact(r, e)));
+
+    let mut plan = Vec::new();
+    let mut claimed = vec![false; actual.len()];
+
+    for record in &expected {
+        if let Some(i) = actual.iter().position(|r| exact(r, record)) {
+            claimed[i] = true;
+            if let Ownership::Comment(_) = &config.ownership {
+                if !is_managed(&actual[i]) && !locked(&actual[i]) && adoptable(owned_name(record, config)) {
+                    plan.push(PlannedChange::new(PlanAction::Update(Record {
+                        id: actual[i].id.clone(),
+                        ..record.clone()
+                    }), "adopting a matching record"));
+                }
+            }
+            continue;
+        }
+
+        let existing = actual.iter()
+            .enumerate()
+            .find(|(i, r)| !claimed[*i] && same(r, record) && !is_foreign(r) && !expected.iter().any(|e| exact(r, e)));
+        if let Some((i, existing)) = existing {
+            if !is_managed(existing) {
+                info!("Skip updating record {} {} not managed by us", &record._type, &record.name);
+                continue;
+            }
+            if locked(existing) {
+                warn!("Skip updating record {} {} locked by Cloudflare", &record._type, &record.name);
+                claimed[i] = true;
+                continue;
+            }
+
+            claimed[i] = true;
+            plan.push(PlannedChange::new(PlanAction::Update(
+                Record {
+                    id: existing.id.clone(),
+                    ..record.clone()
+                }
+            ), update_reason(record, existing)));
+        } else {
+            if not_managed.contains(owned_name(record, config)) && !adoptable(owned_name(record, config)) {
+                info!("Skip creating record {} {} not managed by us", &record._type, &record.name);
+                continue;
+            }
+
+            let reason = if is_marker(record) {
+                format!("ownership marker for {}", owned_name(record, config))
+            } else {
+                "newly expected".to_string()
+            };
+            plan.push(PlannedChange::new(PlanAction::Add(record.clone()), reason));
+        }
+    }
+
+    let stale: Vec<&Record> = actual.iter()
+        .enumerate()
+        .filter(|(i, record)| is_managed(record) && !claimed[*i] && !is_foreign(record) && !is_user_txt(record) &&
+            !predates_management(record, config) && !excluded(record, config))
+        .map(|(_, record)| record)
+        .collect();
+    // With `DELETABLE_TYPES` only records of the listed types are deleted.
+    let deletable = |record: &Record| config.deletable_types.is_empty() ||
+        config.deletable_types.contains(&record._type);
+    let mut deleted = HashSet::new();
+    for record in stale.iter().filter(|r| !is_marker(r)) {
+        if locked(record) {
+            warn!("Skip deleting record {} {} locked by Cloudflare", &record._type, &record.name);
+            continue;
+        }
+        if !deletable(record) {
+            info!("Skip deleting record {} {} not in DELETABLE_TYPES", &record._type, &record.name);
+            continue;
+        }
+        let change = delete(record, config);
+        if let PlanAction::Delete(_) = &change.action {
+            deleted.insert(record.id.clone());
+        }
+        plan.push(change);
+    }
+    // A marker is only deleted along with the last record of its name, a lone stale marker next to records we
+    // keep still covers them.
+    for record in stale.iter().filter(|r| is_marker(r)) {
+        let name = owned_name(record, config);
+        let covers = actual.iter()
+            .any(|r| !is_marker(r) && !is_user_txt(r) && owned_name(r, config) == name && !deleted.contains(&r.id));
+        if covers {
+            info!("Skip deleting marker {} {} still covering records", &record._type, &record.name);
+            continue;
+        }
+        if locked(record) {
+            warn!("Skip deleting record {} {} locked by Cloudflare", &record._type, &record.name);
+            continue;
+        }
+        plan.push(delete(record, config));
+    }
+
+    // A CNAME can't coexist with other records at the same name, so records replaced by a CNAME (or replaced
+    // by other records when a CNAME goes away) are deleted before anything else.
+    let added: Vec<&Record> = plan.iter()
+        .filter_map(|change| match &change.action {
+            PlanAction::Add(record) if !is_marker(record) => Some(record),
+            _ => None
+        })
+        .collect();
+    let conflicting: HashSet<String> = plan.iter()
+        .filter_map(|change| match &change.action {
+            PlanAction::Delete(record) if !is_marker(record) => Some(record),
+            _ => None
+        })
+        .filter(|deleted| added.iter().any(|a| a.name == deleted.name &&
+            (a._type == RecordType::CNAME || deleted._type == RecordType::CNAME)))
+        .map(|deleted| deleted.id.clone())
+        .collect();
+
+    // Ownership markers are created before and deleted after the records they cover, so an interrupted
+    // apply never leaves a record we created without a marker. Other deletes follow all adds and updates, so a
+    // name whose address changes never goes without a record in between.
+    plan.sort_by_key(|change| match &change.action {
+        PlanAction::Delete(record) if conflicting.contains(&record.id) => 0,
+        PlanAction::Add(record) if is_marker(record) => 1,
+        PlanAction::Add(_) => 2,
+        PlanAction::Update(_) => 3,
+        PlanAction::Delete(record) if !is_marker(record) => 4,
+        PlanAction::Delete(_) => 5,
+    });
+
+    // Markers are deleted last, so a throttled cleanup never leaves records we created without one.
+    let deletes = plan.iter().filter(|change| matches!(change.action, PlanAction::Delete(_))).count();
+    if let Some(max) = config.max_deletes.filter(|max| deletes > *max) {
+        warn!("Throttling cleanup to MAX_DELETES_PER_RECONCILE={} of {} deletes, the rest follow in later reconciles",
+              max, deletes);
+        let mut kept = 0;
+        plan.retain(|change| !matches!(change.action, PlanAction::Delete(_)) || {
+            kept += 1;
+            kept <= max
+        });
+    }
+    plan
+}
+
+/// The deduplicated records the resources publish in the zone.
+pub fn expected_records(resources: &[&WatchedResource], resolved: &ResolvedHostnames, config: &Config,
+                        zone: &str) -> Vec<Record> {
+    let records = compute_records(resources.to_vec(), resolved, config)
+        .into_iter()
+        .filter(|r| match &r.zone {
+            Some(pinned) => pinned == zone,
+            None => matches!(zone_for(&r.name, &config.zones), Some(z) if z.name == zone)
+        })
+        .filter(|r| !excluded(r, config))
+        .collect();
+    dedupe_records(records)
+}
+
+// Names matching `NAME_EXCLUDE_REGEX` are neither published nor deleted, markers follow the name they cover.
+fn excluded(record: &Record, config: &Config) -> bool {
+    matches!(&config.name_exclude, Some(pattern) if pattern.is_match(owned_name(record, config)))
+}
+
+/// Hostnames of disabled resources whose records are left alone. A hostname still published by another resource
+/// stays managed.
+pub fn untouched_hostnames(resources: &[&WatchedResource], expected: &[Record]) -> Vec<String> {
+    disabled_hostnames(resources).into_iter()
+        .filter(|hostname| !expected.iter().any(|r| r.name == *hostname))
+        .collect()
+}
+
+/// `MAX_RECORDS` when the resources publish more records than that. The zone isn't reconciled then, so a
+/// misconfigured resource can't flood it.
+pub fn exceeded_max_records(expected: &[Record], config: &Config) -> Option<usize> {
+    config.max_records.filter(|max| expected.len() > *max)
+}
+
+/// The changes of a reconcile, or of one with a host only those of the records published for it, as asked for by
+/// `POST /debug/reconcile`. Records of disabled hostnames are neither updated nor deleted.
+pub fn scoped_changes(expected: &[Record], actual: &[Record], disabled: &[String], host: Option<&str>,
+                      config: &Config) -> Vec<PlannedChange> {
+    let host: Vec<String> = host.map(String::from).into_iter().collect();
+    let in_scope = |record: &Record| host.is_empty() || for_hostnames(record, &host, config);
+    let expected: Vec<Record> = expected.iter().filter(|r| in_scope(r)).cloned().collect();
+    let actual: Vec<Record> = actual.iter()
+        .filter(|r| in_scope(r) && !for_hostnames(r, disabled, config))
+        .cloned()
+        .collect();
+    planned_changes(&expected, &actual, config)
+}
+
+/// The names a reconcile of `host` lists: the host, the names of the records published for it and their markers.
+pub fn host_record_names(host: &str, expected: &[Record], config: &Config) -> Vec<String> {
+    let host = vec![host.to_string()];
+    let names: BTreeSet<&str> = expected.iter()
+        .filter(|r| for_hostnames(r, &host, config))
+        .map(|r| owned_name(r, config))
+        .chain(host.iter().map(String::as_str))
+        .collect();
+    let names: BTreeSet<String> = names.into_iter()
+        .flat_map(|name| vec![name.to_string(), marker_name(name, config)])
+        .collect();
+    names.into_iter().collect()
+}
+
+/// Plans the changes turning the zone's `actual` records into the ones the resources publish, without talking to
+/// the cluster or Cloudflare. Hostnames are resolved beforehand, see `hostnames_to_resolve`. New markers are
+/// stamped as created at `now`. Unlike the controller, no `RecordTimes` hold changes back, every change is planned
+/// right away.
+pub fn compute_plan(resources: &[&WatchedResource], resolved: &ResolvedHostnames, actual: &[Record],
+                    config: &Config, zone: &str, now: SystemTime) -> Vec<PlanAction> {
+    let expected = expected_records(resources, resolved, config, zone);
+    let untouched = untouched_hostnames(resources, &expected);
+    let mut changes = scoped_changes(&expected, actual, &untouched, None, config);
+    stamp_markers(&mut changes, now, config);
+    changes.into_iter()
+        .map(|change| change.action)
+        .collect()
+}
+
+type RecordTimeKey = (RecordType, String, String);
+
+// Markers are keyed by their identity, their details differ between the expected and the published marker.
+fn record_time_key(record: &Record) -> RecordTimeKey {
+    let content = if is_marker(record) {
+        marker_identity(&record.content)
+    } else {
+        canonical_content(record)
+    };
+    (record._type.clone(), canonical_name(&record.name), content)
+}
+
+/// When each expected record was first and last computed, for `PUBLISH_DELAY_SECONDS` and `DELETE_GRACE_SECONDS`.
+#[derive(Default)]
+pub struct RecordTimes {
+    first_seen: HashMap<RecordTimeKey, SystemTime>,
+    last_expected: HashMap<RecordTimeKey, SystemTime>,
+}
+
+impl RecordTimes {
+    // A record that stops being expected starts over when it comes back, and is forgotten once its grace is over.
+    pub fn track(&mut self, expected: &[Record], now: SystemTime, config: &Config) {
+        let keys: HashSet<_> = expected.iter().map(record_time_key).collect();
+        self.first_seen.retain(|key, _| keys.contains(key));
+        match config.delete_grace {
+            Some(grace) => self.last_expected.retain(|_, seen| now.duration_since(*seen).unwrap_or_default() < grace),
+            None => self.last_expected.clear()
+        }
+        for key in keys {
+            self.first_seen.entry(key.clone()).or_insert(now);
+            if config.delete_grace.is_some() {
+                self.last_expected.insert(key, now);
+            }
+        }
+    }
+
+    pub fn hold_back(&self, plan: &mut Vec<PlannedChange>, now: SystemTime, config: &Config) {
+        if let Some(delay) = config.publish_delay {
+            self.delay_unstable(plan, now, delay);
+        }
+        if let Some(grace) = config.delete_grace {
+            self.defer_deletes(plan, now, grace);
+        }
+    }
+
+    // With `PUBLISH_DELAY_SECONDS` new contents are only published once they have been expected for the delay, in
+    // case a fresh LoadBalancer address is transient. Markers aren't delayed, they cover nothing until their records
+    // follow.
+    fn delay_unstable(&self, plan: &mut Vec<PlannedChange>, now: SystemTime, delay: Duration) {
+        plan.retain(|change| match &change.action {
+            PlanAction::Add(record) | PlanAction::Update(record) if !is_marker(record) => {
+                let stable = !matches!(self.first_seen.get(&record_time_key(record)),
+                    Some(seen) if now.duration_since(*seen).unwrap_or_default() < delay);
+                if !stable {
+                    info!("Delaying {} {} {} until it has been expected for {:?}", record._type, record.name,
+                          record.content, delay);
+                }
+                stable
+            }
+            _ => true
+        });
+    }
+
+    // With `DELETE_GRACE_SECONDS` the records of a deleted resource stay until they have been unexpected for the
+    // grace, so a resource that is recreated right away, as by a flapping deploy, keeps its records. Records never
+    // expected since the controller started are deleted right away.
+    fn defer_deletes(&self, plan: &mut Vec<PlannedChange>, now: SystemTime, grace: Duration) {
+        plan.retain(|change| {
+            let record = match &change.action {
+                PlanAction::Delete(record) => record,
+                PlanAction::Update(record) if tombstoned(record) => record,
+                _ => return true
+            };
+            let stale = !matches!(self.last_expected.get(&record_time_key(record)),
+                Some(seen) if now.duration_since(*seen).unwrap_or_default() < grace);
+            if !stale {
+                info!("Deferring deletion of {} {} {} until it has been unexpected for {:?}", record._type,
+                      record.name, record.content, grace);
+            }
+            stale
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::UNIX_EPOCH;
+
+    use crate::clock::{Clock, TestClock};
+
+    use super::*;
+
+    fn service(name: &str, hostname: &str, ip: &str) -> WatchedResource {
+        annotated_service(name, ip, json!({HOSTNAME_LABEL: hostname}))
+    }
+
+    fn annotated_service(name: &str, ip: &str, annotations: Value) -> WatchedResource {
+        WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": name, "namespace": "default", "annotations": annotations},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {"ingress": [{"ip": ip}]}}
+        })).unwrap())
+    }
+
+    fn ingress(name: &str, host: &str, annotations: Value, load_balancer: Value) -> WatchedResource {
+        WatchedResource::Ingress(serde_json::from_value(json!({
+            "metadata": {"name": name, "namespace": "default", "annotations": annotations},
+            "spec": {"rules": [{"host": host}]},
+            "status": {"loadBalancer": {"ingress": load_balancer}}
+        })).unwrap())
+    }
+
+    // The published records besides markers, sorted as "TYPE name content".
+    fn contents(records: &[Record]) -> Vec<String> {
+        let mut contents: Vec<String> = records.iter()
+            .filter(|r| !is_marker(r))
+            .map(|r| format!("{} {} {}", r._type, r.name, r.content))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    fn record(_type: RecordType, name: &str, content: &str) -> Record {
+        Record {
+            id: format!("{} {} {}", _type, name, content),
+            _type,
+            name: name.into(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    fn marker(name: &str) -> Record {
+        record(RecordType::TXT, name, APP_NAME)
+    }
+
+    fn expected(resources: &[WatchedResource], config: &Config) -> Vec<Record> {
+        let resources: Vec<_> = resources.iter().collect();
+        expected_records(&resources, &ResolvedHostnames::new(), config, &config.zones[0].name)
+    }
+
+    fn summary(plan: &[PlannedChange]) -> Vec<String> {
+        let mut summary: Vec<String> = plan.iter().map(|change| describe(&change.action)).collect();
+        summary.sort();
+        summary
+    }
+
+    // An action as "add|update|delete TYPE name content".
+    fn describe(action: &PlanAction) -> String {
+        match action {
+            PlanAction::Add(r) => format!("add {} {} {}", r._type, r.name, r.content),
+            PlanAction::Update(r) => format!("update {} {} {}", r._type, r.name, r.content),
+            PlanAction::Delete(r) => format!("delete {} {} {}", r._type, r.name, r.content),
+        }
+    }
+
+    #[test]
+    fn relative_hostname_with_reverse_zones() {
+        let config = Config::for_test(&[("REVERSE_ZONES", "2.0.192.in-addr.arpa")]);
+        let records = expected(&[service("app", "app", "192.0.2.1")], &config);
+        assert!(records.iter().any(|r| r._type == RecordType::A && r.name == "app.example.com"), "{:?}", records);
+        assert_eq!(hostname_problem("app", &config.zones), None);
+        assert_eq!(hostname_problem("app.other.com", &config.zones),
+                   Some("app.other.com is not within the zones example.com".into()));
+    }
+
+    #[test]
+    fn ptr_records_point_back_to_hostnames() {
+        let v6_zone = "8.b.d.0.1.0.0.2.ip6.arpa";
+        let v6_name = format!("1.{}{}", "0.".repeat(23), v6_zone);
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192.in-addr.arpa");
+        assert_eq!(reverse_name("2001:db8::1".parse().unwrap()), v6_name);
+
+        let config = Config::for_test(&[("REVERSE_ZONES", &format!("2.0.192.in-addr.arpa,{}", v6_zone))]);
+        let resources = [service("v4", "v4.example.com", "192.0.2.1"), service("v6", "v6.example.com", "2001:db8::1"),
+                         service("other", "other.example.com", "198.51.100.1")];
+        let resources: Vec<_> = resources.iter().collect();
+        let ptrs = |zone: &str| expected_records(&resources, &ResolvedHostnames::new(), &config, zone).into_iter()
+            .filter(|r| !is_marker(r))
+            .map(|r| format!("{} {} {}", r._type, r.name, r.content))
+            .collect::<Vec<_>>();
+        assert_eq!(ptrs("2.0.192.in-addr.arpa"), vec!["PTR 1.2.0.192.in-addr.arpa v4.example.com"]);
+        assert_eq!(ptrs(v6_zone), vec![format!("PTR {} v6.example.com", v6_name)]);
+    }
+
+    #[test]
+    fn resource_without_addresses_is_logged() {
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let pending = WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "default", "annotations": {HOSTNAME_LABEL: "app.example.com"}},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {}}
+        })).unwrap());
+        let records = tracing::subscriber::with_default(subscriber, || expected(&[pending], &Config::for_test(&[])));
+        assert!(records.is_empty());
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("hostname app.example.com has no addresses yet"), "{}", logs);
+    }
+
+    #[test]
+    fn caa_record_round_trips_and_converges() {
+        let config = Config::for_test(&[]);
+        let caa = |value: &str| expected(&[annotated_service("app", "192.0.2.1", json!({
+            HOSTNAME_LABEL: "app.example.com", CAA_LABEL: value
+        }))], &config);
+        let expected = caa("0 issue \"letsencrypt.org\"");
+        assert!(contents(&expected).contains(&"CAA app.example.com 0 issue \"letsencrypt.org\"".to_string()));
+
+        // Cloudflare lists the record with its own content formatting and the data fields it derives.
+        let actual: Vec<Record> = published(&expected).into_iter()
+            .map(|r| serde_json::from_value(serde_json::to_value(r).unwrap()).unwrap())
+            .map(|r: Record| match r._type {
+                RecordType::CAA => {
+                    let mut data = r.data.clone().unwrap();
+                    data["mirrored"] = json!(true);
+                    Record { content: "0 issue letsencrypt.org".into(), data: Some(data), ..r }
+                }
+                _ => r
+            })
+            .collect();
+        assert!(planned_changes(&expected, &actual, &config).is_empty());
+
+        let changes = planned_changes(&caa("0 issue \"pki.goog\""), &actual, &config);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].reason, "data changed");
+        match &changes[0].action {
+            PlanAction::Update(record) => assert_eq!(record.data.as_ref().unwrap()["value"], "pki.goog"),
+            action => panic!("{:?}", action)
+        }
+    }
+
+    #[test]
+    fn only_ingresses_of_the_classes_publish() {
+        let config = Config::for_test(&[("INGRESS_CLASS", "nginx-public")]);
+        let lb = json!([{"ip": "192.0.2.1"}]);
+        let mut public = ingress("public", "public.example.com", json!({}), lb.clone());
+        let mut internal = ingress("internal", "internal.example.com", json!({}), lb.clone());
+        for (resource, class) in [(&mut public, "nginx-public"), (&mut internal, "nginx-internal")] {
+            if let WatchedResource::Ingress(ingress) = resource {
+                ingress.spec.as_mut().unwrap().ingress_class_name = Some(class.into());
+            }
+        }
+        let legacy = ingress("legacy", "legacy.example.com", json!({INGRESS_CLASS_ANNOTATION: "nginx-public"}), lb);
+        assert_eq!(contents(&expected(&[public, internal, legacy], &config)), vec![
+            "A legacy.example.com 192.0.2.1",
+            "A public.example.com 192.0.2.1",
+        ]);
+    }
+
+    #[test]
+    fn tls_hosts_are_published_with_include_tls_hosts() {
+        let ingress = WatchedResource::Ingress(serde_json::from_value(json!({
+            "metadata": {"name": "web", "namespace": "default"},
+            "spec": {"rules": [{"host": "web.example.com"}],
+                     "tls": [{"hosts": ["web.example.com", "cert.example.com"]}]},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap());
+        let published = |include: &str| {
+            let config = Config::for_test(&[("INCLUDE_TLS_HOSTS", include)]);
+            contents(&expected_records(&[&ingress], &ResolvedHostnames::new(), &config, "example.com"))
+        };
+        assert_eq!(published("false"), vec!["A web.example.com 192.0.2.1"]);
+        assert_eq!(published("true"), vec!["A cert.example.com 192.0.2.1", "A web.example.com 192.0.2.1"]);
+    }
+
+    #[test]
+    fn node_ports_are_published_with_node_addresses() {
+        let node = |name: &str, external: &str, internal: &str| WatchedResource::Node(serde_json::from_value(json!({
+            "metadata": {"name": name},
+            "status": {"addresses": [{"type": "ExternalIP", "address": external},
+                                     {"type": "InternalIP", "address": internal},
+                                     {"type": "Hostname", "address": name}]}
+        })).unwrap());
+        let resources = [
+            WatchedResource::Service(serde_json::from_value(json!({
+                "metadata": {"name": "app", "namespace": "default", "annotations": {HOSTNAME_LABEL: "app.example.com"}},
+                "spec": {"type": "NodePort"}
+            })).unwrap()),
+            node("a", "198.51.100.2", "10.0.0.2"),
+            node("b", "198.51.100.1", "10.0.0.1"),
+        ];
+        let published = |vars: &[(&str, &str)]| contents(&expected(&resources, &Config::for_test(vars)));
+        assert!(published(&[]).is_empty());
+        assert_eq!(published(&[("NODEPORT_SOURCE", "true")]),
+                   vec!["A app.example.com 198.51.100.1", "A app.example.com 198.51.100.2"]);
+        assert_eq!(published(&[("NODEPORT_SOURCE", "true"), ("NODE_ADDRESS_TYPE", "InternalIP")]),
+                   vec!["A app.example.com 10.0.0.1", "A app.example.com 10.0.0.2"]);
+    }
+
+    #[test]
+    fn ingress_ttl_annotation_applies_to_its_records() {
+        let config = Config::for_test(&[]);
+        let records = expected(&[ingress("app", "app.example.com", json!({TTL_LABEL: "300"}),
+                                         json!([{"ip": "192.0.2.1"}]))], &config);
+        let a = records.iter().find(|r| r._type == RecordType::A).unwrap();
+        assert_eq!((a.name.as_str(), a.ttl), ("app.example.com", Some(300)));
+    }
+
+    #[test]
+    fn only_address_records_are_proxied() {
+        let config = Config::for_test(&[]);
+        let records = expected(&[annotated_service("app", "192.0.2.1", json!({
+            HOSTNAME_LABEL: "app.example.com", PROXIED_LABEL: "true"
+        }))], &config);
+        let proxied: Vec<(RecordType, Option<bool>)> = records.iter().map(|r| (r._type.clone(), r.proxied)).collect();
+        assert!(proxied.contains(&(RecordType::A, Some(true))), "{:?}", proxied);
+        let markers: Vec<&Record> = records.iter().filter(|r| r._type == RecordType::TXT).collect();
+        assert!(!markers.is_empty() && markers.iter().all(|r| r.proxied != Some(true)), "{:?}", proxied);
+    }
+
+    #[test]
+    fn unproxiable_records_are_published_unproxied() {
+        for (ip, public) in [("1.1.1.1", true), ("10.0.0.1", false), ("192.168.1.1", false), ("127.0.0.1", false),
+                             ("2606:4700::1111", true), ("fd00::1", false), ("fe80::1", false)] {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{}", ip);
+        }
+
+        let config = Config::for_test(&[]);
+        let proxied = |ip: &str| expected(&[annotated_service("app", ip, json!({
+            HOSTNAME_LABEL: "app.example.com", PROXIED_LABEL: "true"
+        }))], &config);
+        let added = |expected: &[Record], actual: &[Record]| planned_changes(expected, actual, &config).iter()
+            .filter_map(|change| match &change.action {
+                PlanAction::Add(r) if r._type == RecordType::A => Some(r.proxied),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(added(&proxied("1.1.1.1"), &[]), vec![Some(true)]);
+        assert_eq!(added(&proxied("10.0.0.1"), &[]), vec![Some(false)]);
+
+        // Cloudflare's proxiable flag of a listed record wins over our guess.
+        let actual: Vec<Record> = published(&proxied("1.1.1.1")).into_iter()
+            .map(|r| match r._type {
+                RecordType::A => Record { proxied: Some(false), proxiable: Some(false), ..r },
+                _ => r
+            })
+            .collect();
+        assert!(planned_changes(&proxied("1.1.1.1"), &actual, &config).is_empty());
+    }
+
+    #[test]
+    fn ttl_annotation_accepts_auto_and_seconds() {
+        for (value, ttl) in [("auto", AUTO_TTL), (" auto ", AUTO_TTL), ("1", AUTO_TTL), ("60", 60), ("300", 300),
+                             ("86400", 86400), ("30", AUTO_TTL), ("86401", AUTO_TTL), ("5m", AUTO_TTL)] {
+            assert_eq!(parse_ttl("app.example.com", value), ttl, "{}", value);
+        }
+    }
+
+    #[test]
+    fn address_to_cname_switch_deletes_first() {
+        let config = Config::for_test(&[("TXT_MARKER_PREFIX", "_owner")]);
+        let actual = published(&expected(&[service("app", "app.example.com", "192.0.2.1")], &config));
+        let switched = expected(&[ingress("app", "app.example.com", json!({USE_HOSTNAME_LABEL: "true"}),
+                                          json!([{"hostname": "lb.example.net"}]))], &config);
+        let order: Vec<String> = plan(&switched, &actual, &config).iter().map(describe).collect();
+        assert_eq!(order, vec!["delete A app.example.com 192.0.2.1", "add CNAME app.example.com lb.example.net"]);
+    }
+
+    #[test]
+    fn named_ports_become_srv_records() {
+        let config = Config::for_test(&[]);
+        let with_ports = |annotations: Value| WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "default", "annotations": annotations},
+            "spec": {"type": "LoadBalancer", "ports": [
+                {"name": "http", "port": 80, "protocol": "TCP"},
+                {"name": "dns", "port": 53, "protocol": "UDP"},
+                {"port": 8080},
+            ]},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap());
+        let srv = |annotations: Value| contents(&expected(&[with_ports(annotations)], &config)).into_iter()
+            .filter(|record| record.starts_with("SRV"))
+            .collect::<Vec<_>>();
+        assert_eq!(srv(json!({HOSTNAME_LABEL: "app.example.com", SRV_FROM_PORTS_LABEL: "true"})), vec![
+            "SRV _dns._udp.app.example.com 0 53 app.example.com",
+            "SRV _http._tcp.app.example.com 0 80 app.example.com",
+        ]);
+        assert!(srv(json!({HOSTNAME_LABEL: "app.example.com"})).is_empty());
+    }
+
+    #[test]
+    fn disabled_resource_is_neither_published_nor_deleted() {
+        let config = Config::for_test(&[]);
+        let actual = published(&expected(&[service("app", "app.example.com", "192.0.2.1")], &config));
+        let disabled = annotated_service("app", "192.0.2.2", json!({
+            HOSTNAME_LABEL: "app.example.com", DISABLED_LABEL: "true"
+        }));
+        assert!(expected_records(&[&disabled], &ResolvedHostnames::new(), &config, "example.com").is_empty());
+        let plan = compute_plan(&[&disabled], &ResolvedHostnames::new(), &actual, &config, "example.com",
+                                start().now());
+        assert!(plan.is_empty(), "{:?}", plan);
+    }
+
+    #[test]
+    fn records_created_before_manage_since_are_kept() {
+        let config = Config::for_test(&[("MANAGE_SINCE", "2021-06-01T00:00:00Z")]);
+        // The records of `name` as Cloudflare lists them, created at `created_on`.
+        let listed = |name: &str, created_on: &str| -> Vec<Record> {
+            let records = expected(&[service(name, &format!("{}.example.com", name), "192.0.2.1")], &config);
+            published(&records).iter()
+                .map(|record| {
+                    let mut listed = serde_json::to_value(record).unwrap();
+                    listed["created_on"] = json!(created_on);
+                    serde_json::from_value(listed).unwrap()
+                })
+                .collect()
+        };
+        let actual = [listed("legacy", "2020-01-01T00:00:00.000000Z"), listed("recent", "2021-07-01T00:00:00Z")]
+            .concat();
+        assert_eq!(actual[0].created_on.as_deref(), Some("2020-01-01T00:00:00.000000Z"));
+        assert_eq!(summary(&planned_changes(&[], &actual, &config)), vec![
+            "delete A recent.example.com 192.0.2.1",
+            "delete TXT recent.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn escaped_wildcards_stay_managed() {
+        assert_eq!(canonical_name("\\052.apps.example.com"), "*.apps.example.com");
+        assert_eq!(canonical_name("*.Apps.Example.com."), "*.apps.example.com");
+
+        let config = Config::for_test(&[]);
+        let expected = expected(&[service("apps", "*.apps.example.com", "192.0.2.1")], &config);
+        let actual: Vec<Record> = published(&expected).into_iter()
+            .map(|r| Record { name: r.name.replace("*.", "\\052."), ..r })
+            .collect();
+        assert!(actual.iter().all(|r| r.name == "\\052.apps.example.com"), "{:?}", actual);
+        assert!(planned_changes(&expected, &actual, &config).is_empty());
+        assert_eq!(planned_changes(&[], &actual, &config).len(), 2);
+    }
+
+    #[test]
+    fn every_ingress_rule_gets_records() {
+        let hosts: Vec<String> = (0..20).map(|i| format!("app{:02}.example.com", i)).collect();
+        let rules: Vec<Value> = hosts.iter().map(|host| json!({"host": host})).collect();
+        let ingress = WatchedResource::Ingress(serde_json::from_value(json!({
+            "metadata": {"name": "apps", "namespace": "default"},
+            "spec": {"rules": rules},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap());
+        let records = contents(&expected(&[ingress], &Config::for_test(&[])));
+        let published: Vec<String> = hosts.iter().map(|host| format!("A {} 192.0.2.1", host)).collect();
+        assert_eq!(records, published);
+    }
+
+    #[test]
+    fn proxied_drift_is_updated() {
+        let config = Config::for_test(&[]);
+        let proxied = |name: &str, ip: &str| annotated_service(name, ip, json!({
+            HOSTNAME_LABEL: format!("{}.example.com", name), PROXIED_LABEL: "true"
+        }));
+        let expected = expected(&[proxied("app", "1.1.1.1"), proxied("web", "1.0.0.1")], &config);
+        let actual: Vec<Record> = published(&expected).into_iter()
+            .map(|r| match r._type {
+                RecordType::A => Record { proxied: Some(false), ..r },
+                _ => r
+            })
+            .collect();
+        let changes = planned_changes(&expected, &actual, &config);
+        assert_eq!(summary(&changes), vec!["update A app.example.com 1.1.1.1", "update A web.example.com 1.0.0.1"]);
+        for change in &changes {
+            assert_eq!(change.reason, "proxied changed to true");
+            assert!(matches!(&change.action, PlanAction::Update(r) if r.proxied == Some(true)));
+        }
+    }
+
+    #[test]
+    fn soft_delete_marks_before_deleting() {
+        let config = Config::for_test(&[("SOFT_DELETE", "true")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let actual = published(&expected);
+
+        // The records once the updates and deletes of `changes` are applied.
+        let apply = |actual: &[Record], changes: &[PlannedChange]| -> Vec<Record> {
+            actual.iter()
+                .filter_map(|r| match changes.iter().map(|change| &change.action).find(|action| match action {
+                    PlanAction::Update(change) | PlanAction::Delete(change) => change.id == r.id,
+                    PlanAction::Add(_) => false
+                }) {
+                    Some(PlanAction::Update(update)) => Some(update.clone()),
+                    Some(_) => None,
+                    None => Some(r.clone())
+                })
+                .collect()
+        };
+
+        let marking = planned_changes(&[], &actual, &config);
+        assert_eq!(summary(&marking), vec!["update A app.example.com 192.0.2.1"]);
+        assert_eq!(marking[0].reason, "no longer expected, marking it for deletion");
+        let marked = apply(&actual, &marking);
+        assert!(marked.iter().any(tombstoned));
+
+        // The marker covers its record until that's deleted, then goes through both phases itself.
+        let deleting = planned_changes(&[], &marked, &config);
+        assert_eq!(summary(&deleting), vec![
+            "delete A app.example.com 192.0.2.1",
+            "update TXT app.example.com kube-cloudflare-dns",
+        ]);
+        assert_eq!(summary(&planned_changes(&[], &apply(&marked, &deleting), &config)),
+                   vec!["delete TXT app.example.com kube-cloudflare-dns"]);
+
+        let returned = planned_changes(&expected, &marked, &config);
+        assert_eq!(summary(&returned), vec!["update A app.example.com 192.0.2.1"]);
+        assert_eq!(returned[0].reason, "expected again, removing the deletion mark");
+        assert!(matches!(&returned[0].action, PlanAction::Update(r) if !tombstoned(r)));
+    }
+
+    #[test]
+    fn requested_ip_is_published_before_the_status() {
+        let config = Config::for_test(&[]);
+        let pending = |annotations: Value| WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "default", "annotations": annotations},
+            "spec": {"type": "LoadBalancer", "loadBalancerIP": "192.0.2.7"},
+            "status": {"loadBalancer": {}}
+        })).unwrap());
+        assert_eq!(contents(&expected(&[pending(json!({
+            HOSTNAME_LABEL: "app.example.com", USE_REQUESTED_IP_LABEL: "true"
+        }))], &config)), vec!["A app.example.com 192.0.2.7"]);
+        assert!(expected(&[pending(json!({HOSTNAME_LABEL: "app.example.com"}))], &config).is_empty());
+    }
+
+    #[test]
+    fn lb_select_picks_status_entries() {
+        let config = Config::for_test(&[]);
+        let selected = |select: &str| {
+            let mut annotations = json!({HOSTNAME_LABEL: "app.example.com"});
+            if !select.is_empty() {
+                annotations[LB_SELECT_LABEL] = json!(select);
+            }
+            contents(&expected(&[WatchedResource::Service(serde_json::from_value(json!({
+                "metadata": {"name": "app", "namespace": "default", "annotations": annotations},
+                "spec": {"type": "LoadBalancer"},
+                "status": {"loadBalancer": {"ingress": [
+                    {"ip": "192.0.2.2"}, {"ip": "2001:db8::1"}, {"ip": "192.0.2.1"}
+                ]}}
+            })).unwrap())], &config))
+        };
+        let all = vec![
+            "A app.example.com 192.0.2.1",
+            "A app.example.com 192.0.2.2",
+            "AAAA app.example.com 2001:db8::1",
+        ];
+        assert_eq!(selected(""), all);
+        assert_eq!(selected("all"), all);
+        assert_eq!(selected("first"), vec!["A app.example.com 192.0.2.2"]);
+        assert_eq!(selected("ipv4"), vec!["A app.example.com 192.0.2.1", "A app.example.com 192.0.2.2"]);
+        assert_eq!(selected("ipv6"), vec!["AAAA app.example.com 2001:db8::1"]);
+    }
+
+    #[test]
+    fn conflicting_hostname_goes_to_the_lowest_resource() {
+        let config = Config::for_test(&[]);
+        let winner = vec!["A app.example.com 192.0.2.1"];
+        assert_eq!(contents(&expected(&[service("alpha", "app.example.com", "192.0.2.1"),
+                                        service("zeta", "app.example.com", "192.0.2.2")], &config)), winner);
+        assert_eq!(contents(&expected(&[service("zeta", "app.example.com", "192.0.2.2"),
+                                        service("alpha", "app.example.com", "192.0.2.1")], &config)), winner);
+        assert_eq!(contents(&expected(&[service("alpha", "app.example.com", "192.0.2.1"),
+                                        service("zeta", "app.example.com", "192.0.2.1")], &config)), winner);
+    }
+
+    #[test]
+    fn auto_hostname_template_names_unannotated_services() {
+        let config = Config::for_test(&[("AUTO_HOSTNAME_TEMPLATE", "{name}.{namespace}.svc.example.com")]);
+        let internal = WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "internal", "namespace": "default"},
+            "spec": {"type": "ClusterIP", "clusterIP": "10.0.0.1"}
+        })).unwrap());
+        assert_eq!(contents(&expected(&[annotated_service("api", "192.0.2.1", json!({})),
+                                        service("app", "app.example.com", "192.0.2.2"), internal], &config)), vec![
+            "A api.default.svc.example.com 192.0.2.1",
+            "A app.example.com 192.0.2.2",
+        ]);
+    }
+
+    #[test]
+    fn settings_drift_is_updated() {
+        let config = Config::for_test(&[]);
+        let expected = expected(&[annotated_service("app", "192.0.2.1", json!({
+            HOSTNAME_LABEL: "app.example.com", SETTINGS_LABEL: r#"{"ipv4_only": true}"#
+        }))], &config);
+        let listed = |settings: Value| -> Vec<Record> {
+            published(&expected).into_iter()
+                .map(|r| match r._type {
+                    RecordType::A => {
+                        let mut listed = serde_json::to_value(&r).unwrap();
+                        assert_eq!(listed["settings"], json!({"ipv4_only": true}));
+                        listed["settings"] = settings.clone();
+                        serde_json::from_value(listed).unwrap()
+                    }
+                    _ => r
+                })
+                .collect()
+        };
+        assert!(planned_changes(&expected, &listed(json!({"ipv4_only": true, "ipv6_only": false})), &config)
+            .is_empty());
+        let changes = planned_changes(&expected, &listed(json!({"ipv4_only": false, "ipv6_only": false})), &config);
+        assert_eq!(summary(&changes), vec!["update A app.example.com 192.0.2.1"]);
+        assert_eq!(changes[0].reason, "settings changed");
+    }
+
+    #[test]
+    fn record_type_annotation_filters_contents() {
+        let config = Config::for_test(&[("TXT_MARKER_PREFIX", "_owner")]);
+        let typed = |record_type: &str| contents(&expected(&[WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "default",
+                         "annotations": {HOSTNAME_LABEL: "app.example.com", RECORD_TYPE_LABEL: record_type}},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}, {"ip": "2001:db8::1"}]}}
+        })).unwrap())], &config));
+        assert_eq!(typed("aaaa"), vec!["AAAA app.example.com 2001:db8::1"]);
+        assert!(typed("CNAME").is_empty());
+        assert_eq!(typed("MX"), vec!["A app.example.com 192.0.2.1", "AAAA app.example.com 2001:db8::1"]);
+
+        let cname = ingress("app", "app.example.com", json!({USE_HOSTNAME_LABEL: "true", RECORD_TYPE_LABEL: "CNAME"}),
+                            json!([{"hostname": "lb.example.net"}]));
+        assert_eq!(contents(&expected(&[cname], &config)), vec!["CNAME app.example.com lb.example.net"]);
+    }
+
+    #[test]
+    fn host_annotations_override_the_resource() {
+        let mut annotations = json!({PROXIED_LABEL: "false", TTL_LABEL: "600"});
+        annotations[format!("{}.api.example.com", PROXIED_LABEL)] = json!("true");
+        let ingress = WatchedResource::Ingress(serde_json::from_value(json!({
+            "metadata": {"name": "apps", "namespace": "default", "annotations": annotations},
+            "spec": {"rules": [{"host": "api.example.com"}, {"host": "www.example.com"}]},
+            "status": {"loadBalancer": {"ingress": [{"ip": "1.1.1.1"}]}}
+        })).unwrap());
+        let records = expected(&[ingress], &Config::for_test(&[]));
+        let mut addresses: Vec<(&str, Option<bool>, Option<u32>)> = records.iter()
+            .filter(|r| r._type == RecordType::A)
+            .map(|r| (r.name.as_str(), r.proxied, r.ttl))
+            .collect();
+        addresses.sort();
+        // Proxied records always get the automatic TTL.
+        assert_eq!(addresses, vec![("api.example.com", Some(true), Some(AUTO_TTL)),
+                                   ("www.example.com", Some(false), Some(600))]);
+    }
+
+    #[test]
+    fn resource_version_stamp_causes_no_updates() {
+        let config = Config::for_test(&[("STAMP_RESOURCE_VERSION", "true")]);
+        let versioned = |version: &str, ip: &str| {
+            let mut resource = service("app", "app.example.com", ip);
+            if let WatchedResource::Service(service) = &mut resource {
+                service.metadata.resource_version = Some(version.into());
+            }
+            expected(&[resource], &config)
+        };
+        let actual = published(&versioned("99", "192.0.2.1"));
+        let a = actual.iter().find(|r| r._type == RecordType::A).unwrap();
+        assert_eq!(a.comment.as_deref(), Some("resourceVersion=99"));
+
+        assert!(planned_changes(&versioned("100", "192.0.2.1"), &actual, &config).is_empty());
+        let changes = planned_changes(&versioned("101", "192.0.2.2"), &actual, &config);
+        assert!(matches!(&changes[..], [PlannedChange { action: PlanAction::Update(r), .. }]
+            if r.comment.as_deref() == Some("resourceVersion=101")), "{:?}", changes);
+    }
+
+    #[test]
+    fn zone_annotation_pins_records() {
+        let config = Config::for_test(&[("ZONE_NAME", "example.com,dev.example.com"),
+                                        ("REVERSE_ZONES", "2.0.192.in-addr.arpa")]);
+        let pinned = |zone: &str| annotated_service("app", "192.0.2.1", json!({
+            HOSTNAME_LABEL: "app.dev.example.com", ZONE_LABEL: zone
+        }));
+        let in_zone = |resource: &WatchedResource, zone: &str| -> Vec<String> {
+            expected_records(&[resource], &ResolvedHostnames::new(), &config, zone).iter()
+                .map(|r| format!("{} {} {}", r._type, r.name, r.content))
+                .collect()
+        };
+        let parent = pinned("example.com");
+        assert_eq!(in_zone(&parent, "example.com"), vec![
+            "A app.dev.example.com 192.0.2.1",
+            "TXT app.dev.example.com kube-cloudflare-dns",
+        ]);
+        assert!(in_zone(&parent, "dev.example.com").is_empty());
+        assert_eq!(in_zone(&parent, "2.0.192.in-addr.arpa"), vec![
+            "PTR 1.2.0.192.in-addr.arpa app.dev.example.com",
+            "TXT 1.2.0.192.in-addr.arpa kube-cloudflare-dns",
+        ]);
+
+        // A zone that isn't configured is ignored.
+        let unknown = pinned("other.com");
+        assert!(in_zone(&unknown, "example.com").is_empty());
+        assert_eq!(in_zone(&unknown, "dev.example.com").len(), 2);
+    }
+
+    #[test]
+    fn records_carry_their_source() {
+        let config = Config::for_test(&[("REVERSE_ZONES", "2.0.192.in-addr.arpa")]);
+        let resources = [service("app", "app.example.com", "192.0.2.1"),
+                         ingress("web", "web.example.com", json!({}), json!([{"ip": "192.0.2.2"}]))];
+        let mut sourced: Vec<String> = compute_sourced_records(resources.iter().collect(), &ResolvedHostnames::new(),
+                                                               &config).into_iter()
+            .filter(|(r, _)| !is_marker(r))
+            .map(|(r, key)| format!("{} {} {}: {}", r._type, r.name, r.content, key))
+            .collect();
+        sourced.sort();
+        assert_eq!(sourced, vec![
+            "A app.example.com 192.0.2.1: Service default/app",
+            "A web.example.com 192.0.2.2: Ingress default/web",
+            "PTR 1.2.0.192.in-addr.arpa app.example.com: Service default/app",
+            "PTR 2.2.0.192.in-addr.arpa web.example.com: Ingress default/web",
+        ]);
+    }
+
+    #[test]
+    fn changes_explain_themselves() {
+        let config = Config::for_test(&[]);
+        let actual = published(&expected(&[service("app", "app.example.com", "192.0.2.1"),
+                                           service("old", "old.example.com", "192.0.2.3")], &config));
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.2"),
+                                  service("new", "new.example.com", "192.0.2.4")], &config);
+        let mut reasons: Vec<String> = planned_changes(&expected, &actual, &config).iter()
+            .map(|change| match &change.action {
+                PlanAction::Add(r) => format!("add {} {}: {}", r._type, r.name, change.reason),
+                PlanAction::Update(r) => format!("update {} {}: {}", r._type, r.name, change.reason),
+                PlanAction::Delete(r) => format!("delete {} {}: {}", r._type, r.name, change.reason),
+            })
+            .collect();
+        reasons.sort();
+        assert_eq!(reasons, vec![
+            "add A new.example.com: newly expected",
+            "add TXT new.example.com: ownership marker for new.example.com",
+            "delete A old.example.com: no longer expected",
+            "delete TXT old.example.com: no longer expected",
+            "update A app.example.com: content changed from 192.0.2.1 to 192.0.2.2",
+        ]);
+    }
+
+    #[test]
+    fn prefixed_marker_manages_its_host() {
+        let config = Config::for_test(&[("TXT_MARKER_PREFIX", "_kube-cf-dns")]);
+        let actual = published(&expected(&[service("app", "app.example.com", "192.0.2.1")], &config));
+        let moved = expected(&[service("app", "app.example.com", "192.0.2.2")], &config);
+        assert_eq!(summary(&planned_changes(&moved, &actual, &config)),
+                   vec!["update A app.example.com 192.0.2.2"]);
+        assert_eq!(summary(&planned_changes(&[], &actual, &config)), vec![
+            "delete A app.example.com 192.0.2.1",
+            "delete TXT _kube-cf-dns.app.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn use_hostname_publishes_the_status_hostname() {
+        // A CNAME can't share its name with a marker.
+        let config = Config::for_test(&[("TXT_MARKER_PREFIX", "_owner")]);
+        let use_hostname = json!({USE_HOSTNAME_LABEL: "true"});
+        let both = json!([{"ip": "192.0.2.1", "hostname": "lb.elb.amazonaws.com"}]);
+        assert_eq!(contents(&expected(&[ingress("app", "app.example.com", use_hostname.clone(), both.clone())],
+                                      &config)),
+                   vec!["CNAME app.example.com lb.elb.amazonaws.com"]);
+        assert_eq!(contents(&expected(&[ingress("app", "app.example.com", json!({}), both)], &config)),
+                   vec!["A app.example.com 192.0.2.1"]);
+        assert_eq!(contents(&expected(&[ingress("app", "app.example.com", use_hostname, json!([{"ip": "192.0.2.1"}]))],
+                                      &config)),
+                   vec!["A app.example.com 192.0.2.1"]);
+    }
+
+    fn start() -> TestClock {
+        TestClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+    }
+
+    #[test]
+    fn new_contents_wait_for_publish_delay() {
+        let config = Config::for_test(&[("PUBLISH_DELAY_SECONDS", "60")]);
+        let clock = start();
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let mut times = RecordTimes::default();
+
+        times.track(&expected, clock.now(), &config);
+        let mut changes = planned_changes(&expected, &[], &config);
+        times.hold_back(&mut changes, clock.now(), &config);
+        assert_eq!(summary(&changes), vec!["add TXT app.example.com kube-cloudflare-dns"]);
+
+        clock.advance(Duration::from_secs(60));
+        times.track(&expected, clock.now(), &config);
+        let mut changes = planned_changes(&expected, &[], &config);
+        times.hold_back(&mut changes, clock.now(), &config);
+        assert_eq!(summary(&changes), vec![
+            "add A app.example.com 192.0.2.1",
+            "add TXT app.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn deleted_resource_keeps_records_for_delete_grace() {
+        let config = Config::for_test(&[("DELETE_GRACE_SECONDS", "60")]);
+        let clock = start();
+        let actual = vec![
+            record(RecordType::A, "app.example.com", "192.0.2.1"),
+            marker("app.example.com"),
+            record(RecordType::A, "old.example.com", "192.0.2.2"),
+            marker("old.example.com"),
+        ];
+        let mut times = RecordTimes::default();
+        let published = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        times.track(&published, clock.now(), &config);
+
+        // The Service is deleted, its records stay during the grace while those of old.example.com, never
+        // expected since the start, go right away.
+        clock.advance(Duration::from_secs(30));
+        times.track(&[], clock.now(), &config);
+        let mut changes = planned_changes(&[], &actual, &config);
+        times.hold_back(&mut changes, clock.now(), &config);
+        assert_eq!(summary(&changes), vec![
+            "delete A old.example.com 192.0.2.2",
+            "delete TXT old.example.com kube-cloudflare-dns",
+        ]);
+
+        clock.advance(Duration::from_secs(30));
+        times.track(&[], clock.now(), &config);
+        let mut changes = planned_changes(&[], &actual[..2], &config);
+        times.hold_back(&mut changes, clock.now(), &config);
+        assert_eq!(summary(&changes), vec![
+            "delete A app.example.com 192.0.2.1",
+            "delete TXT app.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn apex_host_reconcile_leaves_other_names_alone() {
+        let config = Config::for_test(&[]);
+        let expected = expected(&[service("apex", "example.com", "192.0.2.1")], &config);
+        let published = expected.iter().find(|r| is_marker(r)).unwrap();
+        let marker = |name: &str| Record { name: name.into(), ..published.clone() };
+        let actual = vec![
+            marker("example.com"),
+            record(RecordType::A, "old.example.com", "192.0.2.2"),
+            marker("old.example.com"),
+            record(RecordType::SRV, "_sip._tcp.old.example.com", "10 5060 old.example.com"),
+        ];
+        let changes = scoped_changes(&expected, &actual, &[], Some("example.com"), &config);
+        assert_eq!(summary(&changes), vec!["add A example.com 192.0.2.1"]);
+
+        let changes = scoped_changes(&expected, &actual, &["old.example.com".into()], None, &config);
+        assert_eq!(summary(&changes), vec!["add A example.com 192.0.2.1"]);
+    }
+
+    #[test]
+    fn host_reconcile_lists_the_host_and_its_marker() {
+        let config = Config::for_test(&[("TXT_MARKER_PREFIX", "_owner")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1"),
+                                  service("other", "other.example.com", "192.0.2.2")], &config);
+        assert_eq!(host_record_names("app.example.com", &expected, &config),
+                   vec!["_owner.app.example.com", "app.example.com"]);
+    }
+
+    #[test]
+    fn apex_records_are_planned() {
+        assert!(in_zone("example.com", "example.com"));
+        let config = Config::for_test(&[]);
+        let records = expected(&[service("apex", "example.com", "192.0.2.1")], &config);
+        assert_eq!(summary(&planned_changes(&records, &[], &config)), vec![
+            "add A example.com 192.0.2.1",
+            "add TXT example.com kube-cloudflare-dns",
+        ]);
+
+        let config = Config::for_test(&[("OWNERSHIP", "comment")]);
+        let apex = WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "apex", "namespace": "default", "annotations": {
+                HOSTNAME_LABEL: "example.com",
+                CONTENT_TEMPLATE_LABEL: "lb.example.net",
+            }},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap());
+        let records = expected(&[apex], &config);
+        assert_eq!(summary(&planned_changes(&records, &[], &config)), vec!["add CNAME example.com lb.example.net"]);
+    }
+
+    #[test]
+    fn apex_conflict_keeps_the_preferred_type() {
+        let key = |name: &str| ResourceKey { kind: "Service".into(), namespace: "default".into(), name: name.into() };
+        let resolved = |vars: &[(&str, &str)]| {
+            let records = vec![
+                (record(RecordType::A, "example.com", "192.0.2.1"), key("ip")),
+                (record(RecordType::CNAME, "example.com", "lb.example.net"), key("lb")),
+                (record(RecordType::A, "app.example.com", "192.0.2.1"), key("ip")),
+            ];
+            contents(&resolve_apex(records, &Config::for_test(vars)).into_iter().map(|(r, _)| r).collect::<Vec<_>>())
+        };
+        assert_eq!(resolved(&[("TXT_MARKER_PREFIX", "_owner")]),
+                   vec!["A app.example.com 192.0.2.1", "A example.com 192.0.2.1"]);
+        assert_eq!(resolved(&[("TXT_MARKER_PREFIX", "_owner"), ("APEX_PREFER", "CNAME")]),
+                   vec!["A app.example.com 192.0.2.1", "CNAME example.com lb.example.net"]);
+    }
+
+    #[test]
+    fn wildcard_points_at_the_controller_service() {
+        let config = Config::for_test(&[("WILDCARD_HOSTNAME", "*.example.com"),
+                                        ("WILDCARD_SERVICE", "default/ingress-nginx")]);
+        let controller = annotated_service("ingress-nginx", "192.0.2.10", json!({}));
+        let app = ingress("app", "app.example.com", json!({}), json!([{"ip": "192.0.2.10"}]));
+        assert_eq!(contents(&expected(&[controller, app], &config)),
+                   vec!["A *.example.com 192.0.2.10", "A app.example.com 192.0.2.10"]);
+
+        // A resource publishing the wildcard hostname itself keeps it.
+        let controller = annotated_service("ingress-nginx", "192.0.2.10", json!({}));
+        let explicit = service("wildcard", "*.example.com", "192.0.2.20");
+        assert_eq!(contents(&expected(&[controller, explicit], &config)), vec!["A *.example.com 192.0.2.20"]);
+        assert!(contents(&expected(&[service("other", "other.example.com", "192.0.2.1")], &config))
+            .iter().all(|r| !r.contains('*')));
+    }
+
+    #[test]
+    fn zones_match_whole_labels() {
+        assert!(in_zone("app.example.com", "example.com"));
+        assert!(!in_zone("myexample.com", "example.com"));
+        assert!(!in_zone("app.myexample.com", "example.com"));
+        let zones = Config::for_test(&[("ZONE_NAME", "example.com,myexample.com")]).zones;
+        assert_eq!(zone_for("app.myexample.com", &zones).map(|zone| zone.name.as_str()), Some("myexample.com"));
+        assert_eq!(hostname_problem("myexample.com", &zones[..1]),
+                   Some("myexample.com is not within the zones example.com".into()));
+    }
+
+    #[test]
+    fn cname_needs_its_marker_moved_aside() {
+        let resources = [WatchedResource::Service(serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "default", "annotations": {
+                HOSTNAME_LABEL: "app.example.com",
+                CONTENT_TEMPLATE_LABEL: "lb.example.net",
+            }},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap())];
+        let names = |config: &Config| {
+            let mut names: Vec<String> = expected(&resources, config).iter()
+                .map(|r| format!("{} {}", r._type, r.name))
+                .collect();
+            names.sort();
+            names
+        };
+        assert!(names(&Config::for_test(&[])).is_empty());
+        assert_eq!(names(&Config::for_test(&[("TXT_MARKER_PREFIX", "owner")])),
+                   vec!["CNAME app.example.com", "TXT owner.app.example.com"]);
+        assert_eq!(names(&Config::for_test(&[("OWNERSHIP", "comment")])), vec!["CNAME app.example.com"]);
+    }
+
+    // The records as Cloudflare lists them once published.
+    fn published(records: &[Record]) -> Vec<Record> {
+        records.iter()
+            .map(|r| Record { id: format!("{} {} {}", r._type, r.name, r.content), ..r.clone() })
+            .collect()
+    }
+
+    #[test]
+    fn only_readiness_conditions_gate_services() {
+        let config = Config::for_test(&[("REQUIRE_READY", "true")]);
+        let with_condition = |type_: &str, status: &str| {
+            let mut service = service("app", "app.example.com", "192.0.2.1");
+            if let WatchedResource::Service(service) = &mut service {
+                service.status.as_mut().unwrap().conditions = Some(vec![serde_json::from_value(json!({
+                    "type": type_, "status": status, "reason": "Test", "message": "",
+                    "lastTransitionTime": "2021-10-01T00:00:00Z"
+                })).unwrap()]);
+            }
+            expected(&[service], &config)
+        };
+        assert!(with_condition("Ready", "False").is_empty());
+        assert!(!with_condition("Ready", "True").is_empty());
+        assert!(!with_condition("LoadBalancerPortsError", "False").is_empty());
+    }
+
+    #[test]
+    fn adopt_marks_matching_records_only() {
+        let config = Config::for_test(&[("ADOPT", "true")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let unmarked: Vec<Record> = published(&expected).into_iter().filter(|r| !is_marker(r)).collect();
+        assert_eq!(summary(&planned_changes(&expected, &unmarked, &config)),
+                   vec!["add TXT app.example.com kube-cloudflare-dns"]);
+
+        // A name with records we wouldn't publish stays someone else's.
+        let differing: Vec<Record> = unmarked.into_iter()
+            .map(|r| Record { content: "192.0.2.9".into(), ..r })
+            .collect();
+        assert!(planned_changes(&expected, &differing, &config).is_empty());
+        assert!(planned_changes(&expected, &differing, &Config::for_test(&[])).is_empty());
+    }
+
+    #[test]
+    fn orphaned_record_gets_its_marker_back() {
+        let config = Config::for_test(&[("RECLAIM_ORPHANS", "true")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let actual: Vec<Record> = published(&expected).into_iter().filter(|r| !is_marker(r)).collect();
+        assert_eq!(summary(&planned_changes(&expected, &actual, &config)),
+                   vec!["add TXT app.example.com kube-cloudflare-dns"]);
+    }
+
+    #[test]
+    fn bare_marker_migrates_to_owner() {
+        let config = Config::for_test(&[("OWNER_ID", "a"), ("MIGRATE_BARE_MARKERS", "true")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let actual: Vec<Record> = published(&expected).into_iter()
+            .map(|r| match is_marker(&r) {
+                true => Record { content: APP_NAME.into(), ..r },
+                false => r
+            })
+            .collect();
+        assert_eq!(summary(&planned_changes(&expected, &actual, &config)),
+                   vec!["update TXT app.example.com kube-cloudflare-dns,owner=a"]);
+    }
+
+    #[test]
+    fn bare_marker_belongs_to_no_owner() {
+        let bare = Config::for_test(&[]);
+        let actual = published(&expected(&[service("app", "app.example.com", "192.0.2.1")], &bare));
+        for owner in &["a", "b"] {
+            let config = Config::for_test(&[("OWNER_ID", owner)]);
+            assert!(planned_changes(&[], &actual, &config).is_empty());
+        }
+        assert_eq!(planned_changes(&[], &actual, &bare).len(), 2);
+    }
+
+    #[test]
+    fn owners_leave_each_other_alone() {
+        let a = Config::for_test(&[("OWNER_ID", "a")]);
+        let b = Config::for_test(&[("OWNER_ID", "b")]);
+        let app = expected(&[service("app", "app.example.com", "192.0.2.1")], &a);
+        let other = expected(&[service("other", "other.example.com", "192.0.2.2")], &b);
+        let actual = published(&[app.clone(), other.clone()].concat());
+
+        assert!(planned_changes(&app, &actual, &a).is_empty());
+        assert!(planned_changes(&other, &actual, &b).is_empty());
+        assert_eq!(summary(&planned_changes(&[], &actual, &a)), vec![
+            "delete A app.example.com 192.0.2.1",
+            "delete TXT app.example.com kube-cloudflare-dns,owner=a",
+        ]);
+    }
+
+    #[test]
+    fn marker_details_cause_no_churn() {
+        let config = Config::for_test(&[("OWNER_ID", "a"), ("TXT_MARKER_FIELDS", "version,created")]);
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let actual: Vec<Record> = published(&expected).into_iter()
+            .map(|r| match is_marker(&r) {
+                true => Record { content: format!("{},version=0.0.1,created=2019-01-01T00:00:00Z", r.content), ..r },
+                false => r
+            })
+            .collect();
+        let mut changes = planned_changes(&expected, &actual, &config);
+        stamp_markers(&mut changes, start().now(), &config);
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn quoted_markers_are_recognized() {
+        let config = Config::for_test(&[]);
+        let quote = |r: Record| match is_marker(&r) {
+            true => Record { content: format!(" \"{}\"", r.content), ..r },
+            false => r
+        };
+        let actual: Vec<Record> = published(&expected(&[service("app", "app.example.com", "192.0.2.1"),
+                                                        service("old", "old.example.com", "192.0.2.3")], &config))
+            .into_iter()
+            .map(quote)
+            .collect();
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        assert_eq!(summary(&planned_changes(&expected, &actual, &config)), vec![
+            "delete A old.example.com 192.0.2.3",
+            "delete TXT old.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn user_txt_coexists_with_our_marker() {
+        let config = Config::for_test(&[]);
+        let spf = record(RecordType::TXT, "example.com", "v=spf1 include:_spf.example.net ~all");
+        let expected = expected(&[service("apex", "example.com", "192.0.2.1")], &config);
+        assert_eq!(summary(&planned_changes(&expected, std::slice::from_ref(&spf), &config)), vec![
+            "add A example.com 192.0.2.1",
+            "add TXT example.com kube-cloudflare-dns",
+        ]);
+
+        let mut actual = published(&expected);
+        actual.push(spf);
+        assert!(planned_changes(&expected, &actual, &config).is_empty());
+        assert_eq!(summary(&planned_changes(&[], &actual, &config)), vec![
+            "delete A example.com 192.0.2.1",
+            "delete TXT example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn locked_records_are_left_alone() {
+        let config = Config::for_test(&[]);
+        let lock = |r: Record| Record { locked: Some(true), ..r };
+        let actual: Vec<Record> = published(&expected(&[service("app", "app.example.com", "192.0.2.1"),
+                                                        service("old", "old.example.com", "192.0.2.3")], &config))
+            .into_iter()
+            .map(|r| match r._type {
+                RecordType::A if r.name == "app.example.com" => lock(Record { content: "192.0.2.9".into(), ..r }),
+                RecordType::A => lock(r),
+                _ => r
+            })
+            .collect();
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        // The locked old record still needs its marker, so neither is deleted.
+        assert!(planned_changes(&expected, &actual, &config).is_empty());
+    }
+
+    #[test]
+    fn deletes_are_capped_per_reconcile() {
+        let config = Config::for_test(&[("MAX_DELETES_PER_RECONCILE", "3")]);
+        let stale: Vec<WatchedResource> = (1..=3)
+            .map(|i| service(&format!("app{}", i), &format!("app{}.example.com", i), &format!("192.0.2.{}", i)))
+            .collect();
+        let actual = published(&expected(&stale, &config));
+        let expected = expected(&[service("new", "new.example.com", "192.0.2.9")], &config);
+        assert_eq!(summary(&planned_changes(&expected, &actual, &config)), vec![
+            "add A new.example.com 192.0.2.9",
+            "add TXT new.example.com kube-cloudflare-dns",
+            "delete A app1.example.com 192.0.2.1",
+            "delete A app2.example.com 192.0.2.2",
+            "delete A app3.example.com 192.0.2.3",
+        ]);
+    }
+
+    #[test]
+    fn undeletable_types_keep_their_marker() {
+        let config = Config::for_test(&[("DELETABLE_TYPES", "A")]);
+        let app = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let v6 = expected(&[service("v6", "v6.example.com", "2001:db8::1")], &config);
+        assert!(v6.iter().any(|r| r._type == RecordType::AAAA), "{:?}", v6);
+        let actual = published(&[app, v6].concat());
+        assert_eq!(summary(&planned_changes(&[], &actual, &config)), vec![
+            "delete A app.example.com 192.0.2.1",
+            "delete TXT app.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn excluded_names_are_neither_published_nor_deleted() {
+        let config = Config::for_test(&[("NAME_EXCLUDE_REGEX", "^internal\\.")]);
+        let internal = expected(&[service("internal", "internal.example.com", "192.0.2.1")], &config);
+        assert!(internal.is_empty(), "{:?}", internal);
+        let app = expected(&[service("app", "app.example.com", "192.0.2.2")], &config);
+        assert_eq!(summary(&planned_changes(&app, &[], &config)), vec![
+            "add A app.example.com 192.0.2.2",
+            "add TXT app.example.com kube-cloudflare-dns",
+        ]);
+
+        let unfiltered = Config::for_test(&[]);
+        let actual = published(&expected(&[service("internal", "internal.example.com", "192.0.2.1"),
+                                             service("old", "old.example.com", "192.0.2.3")], &unfiltered));
+        assert_eq!(summary(&planned_changes(&[], &actual, &config)), vec![
+            "delete A old.example.com 192.0.2.3",
+            "delete TXT old.example.com kube-cloudflare-dns",
+        ]);
+    }
+
+    #[test]
+    fn deletes_follow_adds_and_updates() {
+        let config = Config::for_test(&[]);
+        let old = published(&expected(&[service("app", "old.example.com", "192.0.2.1"),
+                                         service("web", "web.example.com", "192.0.2.2")], &config));
+        let new = expected(&[service("app", "new.example.com", "192.0.2.1"),
+                             service("web", "web.example.com", "192.0.2.3")], &config);
+        let order: Vec<String> = planned_changes(&new, &old, &config).iter()
+            .map(|change| match &change.action {
+                PlanAction::Add(r) => format!("add {} {}", r._type, r.name),
+                PlanAction::Update(r) => format!("update {} {}", r._type, r.name),
+                PlanAction::Delete(r) => format!("delete {} {}", r._type, r.name),
+            })
+            .collect();
+        assert_eq!(order, vec![
+            "add TXT new.example.com",
+            "add A new.example.com",
+            "update A web.example.com",
+            "delete A old.example.com",
+            "delete TXT old.example.com",
+        ]);
+    }
+
+    #[test]
+    fn too_many_records_skip_the_reconcile() {
+        let resources = [
+            service("app", "app.example.com", "192.0.2.1"),
+            service("web", "web.example.com", "192.0.2.2"),
+        ];
+        let config = Config::for_test(&[("MAX_RECORDS", "4")]);
+        assert_eq!(exceeded_max_records(&expected(&resources, &config), &config), None);
+        let config = Config::for_test(&[("MAX_RECORDS", "3")]);
+        assert_eq!(exceeded_max_records(&expected(&resources, &config), &config), Some(3));
+        let config = Config::for_test(&[]);
+        assert_eq!(exceeded_max_records(&expected(&resources, &config), &config), None);
+    }
+
+    #[test]
+    fn hosts_outside_the_zones_become_custom_hostnames() {
+        let ingress = WatchedResource::Ingress(serde_json::from_value(json!({
+            "metadata": {"name": "shop", "namespace": "default"},
+            "spec": {"rules": [
+                {"host": "app.example.com"},
+                {"host": "shop.customer.com"},
+                {"host": "new.customer.com"},
+            ]},
+            "status": {"loadBalancer": {"ingress": [{"ip": "192.0.2.1"}]}}
+        })).unwrap());
+        let expected = custom_hostnames(&[&ingress], &Config::for_test(&[]));
+        assert_eq!(expected.iter().collect::<Vec<_>>(), vec!["new.customer.com", "shop.customer.com"]);
+        let actual = [CustomHostname { id: "h1".into(), hostname: "shop.customer.com".into() }];
+        assert_eq!(missing_custom_hostnames(&expected, &actual), vec!["new.customer.com"]);
+    }
+
+    #[test]
+    fn markers_are_stamped_with_the_clock() {
+        let config = Config::for_test(&[("TXT_MARKER_FIELDS", "version,created")]);
+        let clock = start();
+        let expected = expected(&[service("app", "app.example.com", "192.0.2.1")], &config);
+        let mut changes = planned_changes(&expected, &[], &config);
+        stamp_markers(&mut changes, clock.now(), &config);
+        assert!(summary(&changes).contains(&format!(
+            "add TXT app.example.com kube-cloudflare-dns,version={},created=2020-09-13T12:26:40Z", VERSION)));
+    }
+
+    #[test]
+    fn compute_plan_publishes_and_cleans_up() {
+        let config = Config::for_test(&[("TXT_MARKER_FIELDS", "created")]);
+        let app = service("app", "app.example.com", "192.0.2.1");
+        let actual = published(&expected(&[service("old", "old.example.com", "192.0.2.2")], &config));
+        let plan = compute_plan(&[&app], &ResolvedHostnames::new(), &actual, &config, "example.com", start().now());
+        let summary: Vec<String> = plan.iter().map(describe).collect();
+        assert_eq!(summary, vec![
+            "add TXT app.example.com kube-cloudflare-dns,created=2020-09-13T12:26:40Z",
+            "add A app.example.com 192.0.2.1",
+            "delete A old.example.com 192.0.2.2",
+            "delete TXT old.example.com kube-cloudflare-dns",
+        ]);
+    }
 }
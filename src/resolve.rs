@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::net::lookup_host;
+use tokio::time::timeout;
+use tracing::warn;
+
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type ResolvedHostnames = HashMap<String, Vec<IpAddr>>;
+
+pub async fn resolve_hostnames(hostnames: Vec<String>) -> ResolvedHostnames {
+    let mut resolved = ResolvedHostnames::new();
+    for hostname in hostnames {
+        if resolved.contains_key(&hostname) {
+            continue;
+        }
+
+        let lookup = timeout(RESOLVE_TIMEOUT, lookup_host((hostname.as_str(), 0))).await
+            .map(|r| r.map(|addrs| addrs.map(|a| a.ip()).collect::<Vec<IpAddr>>()));
+        match lookup {
+            Ok(Ok(mut ips)) => {
+                ips.sort();
+                ips.dedup();
+                resolved.insert(hostname, ips);
+            }
+            Ok(Err(err)) => warn!("Failed to resolve {}: {}", hostname, err),
+            Err(_) => warn!("Timed out resolving {}", hostname)
+        }
+    }
+    resolved
+}
@@ -1,8 +1,18 @@
-use k8s_openapi::api::core::v1::Service;
+use std::fmt::{self, Display, Formatter};
+
+use k8s_openapi::{Metadata, NamespaceResourceScope};
+use k8s_openapi::api::core::v1::{Node, Service};
 use k8s_openapi::api::networking::v1::Ingress;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::Resource;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde_json::Value;
+
+use crate::plan::skip_proxy_addresses;
+use crate::traefik::IngressRoute;
 
-#[derive(Hash, PartialEq, Eq, Debug)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
 pub struct ResourceKey {
     pub kind: String,
     pub namespace: String,
@@ -15,15 +25,44 @@ impl ResourceKey {
         Self {
             kind: T::kind(&Default::default()).into(),
             name: res.meta().name.clone().unwrap(),
-            namespace: res.meta().namespace.clone().unwrap(),
+            namespace: res.meta().namespace.clone().unwrap_or_default(),
         }
     }
 }
 
+impl Display for ResourceKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}/{}", self.kind, self.namespace, self.name)
+    }
+}
+
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum WatchedResource {
     Ingress(Ingress),
     Service(Service),
+    IngressRoute(IngressRoute),
+    Node(Node),
+}
+
+impl WatchedResource {
+    pub fn key(&self) -> ResourceKey {
+        match self {
+            WatchedResource::Ingress(ingress) => ResourceKey::from(ingress),
+            WatchedResource::Service(service) => ResourceKey::from(service),
+            WatchedResource::IngressRoute(route) => ResourceKey::from(route),
+            WatchedResource::Node(node) => ResourceKey::from(node),
+        }
+    }
+
+    pub fn metadata(&self) -> &ObjectMeta {
+        match self {
+            WatchedResource::Ingress(ingress) => &ingress.metadata,
+            WatchedResource::Service(service) => &service.metadata,
+            WatchedResource::IngressRoute(route) => &route.metadata,
+            WatchedResource::Node(node) => &node.metadata,
+        }
+    }
 }
 
 impl From<Service> for WatchedResource {
@@ -32,8 +71,113 @@ impl From<Service> for WatchedResource {
     }
 }
 
+/// A watched Service along with the LoadBalancer IPs it reports with `ipMode: Proxy`. The pinned Kubernetes API
+/// types predate `ipMode` and drop it, so it's read from the raw object.
+#[derive(Debug, Clone)]
+pub struct WatchedService {
+    pub service: Service,
+    pub proxy_ips: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for WatchedService {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let proxy_ips = value.pointer("/status/loadBalancer/ingress")
+            .and_then(Value::as_array)
+            .map(|ingress| ingress.iter()
+                .filter(|entry| entry["ipMode"] == "Proxy")
+                .filter_map(|entry| entry["ip"].as_str().map(String::from))
+                .collect())
+            .unwrap_or_default();
+        Ok(Self {
+            service: serde_json::from_value(value).map_err(D::Error::custom)?,
+            proxy_ips,
+        })
+    }
+}
+
+impl k8s_openapi::Resource for WatchedService {
+    const API_VERSION: &'static str = <Service as k8s_openapi::Resource>::API_VERSION;
+    const GROUP: &'static str = <Service as k8s_openapi::Resource>::GROUP;
+    const KIND: &'static str = <Service as k8s_openapi::Resource>::KIND;
+    const VERSION: &'static str = <Service as k8s_openapi::Resource>::VERSION;
+    const URL_PATH_SEGMENT: &'static str = <Service as k8s_openapi::Resource>::URL_PATH_SEGMENT;
+    type Scope = NamespaceResourceScope;
+}
+
+impl Metadata for WatchedService {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.service.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.service.metadata
+    }
+}
+
+impl From<WatchedService> for WatchedResource {
+    fn from(watched: WatchedService) -> Self {
+        let WatchedService { mut service, proxy_ips } = watched;
+        skip_proxy_addresses(&mut service, &proxy_ips);
+        Self::Service(service)
+    }
+}
+
 impl From<Ingress> for WatchedResource {
     fn from(ingress: Ingress) -> Self {
         Self::Ingress(ingress)
     }
 }
+
+impl From<IngressRoute> for WatchedResource {
+    fn from(route: IngressRoute) -> Self {
+        Self::IngressRoute(route)
+    }
+}
+
+impl From<Node> for WatchedResource {
+    fn from(node: Node) -> Self {
+        Self::Node(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::PUBLISH_PROXY_IPS_LABEL;
+
+    use super::*;
+
+    fn service(annotations: Value) -> WatchedResource {
+        serde_json::from_value::<WatchedService>(json!({
+            "metadata": {"name": "app", "namespace": "default", "annotations": annotations},
+            "spec": {"type": "LoadBalancer"},
+            "status": {"loadBalancer": {"ingress": [
+                {"ip": "192.0.2.1", "ipMode": "VIP"},
+                {"ip": "192.0.2.2", "ipMode": "Proxy"},
+                {"ip": "192.0.2.3"},
+            ]}}
+        })).unwrap().into()
+    }
+
+    fn ips(resource: &WatchedResource) -> Vec<&str> {
+        match resource {
+            WatchedResource::Service(service) => service.status.as_ref()
+                .and_then(|status| status.load_balancer.as_ref())
+                .and_then(|lb| lb.ingress.as_ref())
+                .map(|ingress| ingress.iter().filter_map(|entry| entry.ip.as_deref()).collect())
+                .unwrap_or_default(),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn proxy_mode_addresses_are_skipped() {
+        assert_eq!(ips(&service(json!({}))), vec!["192.0.2.1", "192.0.2.3"]);
+        assert_eq!(ips(&service(json!({PUBLISH_PROXY_IPS_LABEL: "true"}))),
+                   vec!["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+    }
+}
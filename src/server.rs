@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, Mutex};
+use tracing::{error, warn};
+
+use crate::api::Record;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, ZoneConfig};
+use crate::metrics::Metrics;
+use crate::plan::{PlanAction, PlannedChange};
+use crate::webhook;
+
+/// A hostname to reconcile right away and where to send the planned changes.
+pub type ReconcileRequest = (String, oneshot::Sender<Result<Vec<PlannedChange>, String>>);
+
+#[derive(Serialize, Default)]
+pub struct DebugState {
+    pub expected: Vec<Record>,
+    pub actual: Vec<Record>,
+    pub plan: Vec<PlannedChange>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub zone: String,
+    pub timestamp: u64,
+    pub add: usize,
+    pub update: usize,
+    pub delete: usize,
+    pub changes: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    fn new(zone: &str, result: &Result<Vec<PlanAction>, String>, clock: &dyn Clock) -> Self {
+        let plan = result.as_ref().map(Vec::as_slice).unwrap_or_default();
+        let count = |f: fn(&PlanAction) -> bool| plan.iter().filter(|action| f(action)).count();
+        Self {
+            zone: zone.into(),
+            timestamp: clock.timestamp(),
+            add: count(|a| matches!(a, PlanAction::Add(_))),
+            update: count(|a| matches!(a, PlanAction::Update(_))),
+            delete: count(|a| matches!(a, PlanAction::Delete(_))),
+            changes: plan.iter()
+                .map(|action| {
+                    let (change, record) = match action {
+                        PlanAction::Add(record) => ("add", record),
+                        PlanAction::Update(record) => ("update", record),
+                        PlanAction::Delete(record) => ("delete", record),
+                    };
+                    format!("{} {} {} {}", change, record._type, record.name, record.content)
+                })
+                .collect(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct Readiness {
+    pub last_success_timestamp: Option<u64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+impl Readiness {
+    pub fn succeeded(&mut self, clock: &dyn Clock) {
+        self.last_success_timestamp = Some(clock.timestamp());
+        self.last_error = None;
+        self.consecutive_failures = 0;
+    }
+
+    pub fn failed(&mut self, err: impl Display) {
+        self.last_error = Some(err.to_string());
+        self.consecutive_failures += 1;
+    }
+
+    fn ready(&self, max_failures: u32, max_age: Duration, clock: &dyn Clock) -> bool {
+        self.consecutive_failures < max_failures && matches!(self.last_success_timestamp,
+            Some(t) if clock.timestamp().saturating_sub(t) <= max_age.as_secs())
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerState {
+    pub debug: Arc<Mutex<BTreeMap<String, DebugState>>>,
+    pub metrics: Arc<Metrics>,
+    pub readiness: Arc<Mutex<BTreeMap<String, Readiness>>>,
+    pub paused: Arc<AtomicBool>,
+    leader: Arc<AtomicBool>,
+    pub clock: Arc<dyn Clock>,
+    pub reconcile_requests: Option<Sender<ReconcileRequest>>,
+    history: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    history_size: usize,
+    webhook_zones: Option<Vec<ZoneConfig>>,
+    ready_max_failures: u32,
+    ready_max_age: Duration,
+}
+
+impl ServerState {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self::with_clock(config, metrics, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: &Config, metrics: Arc<Metrics>, clock: Arc<dyn Clock>) -> Self {
+        // Without leader election, or in a `ONCE` run that already holds the lease, the instance always reconciles.
+        let leader = config.once || !config.leader_election;
+        metrics.leader.set(if leader { 1 } else { 0 });
+        Self {
+            debug: Arc::new(Mutex::new(config.zones.iter()
+                .map(|zone| (zone.name.clone(), DebugState::default()))
+                .collect())),
+            metrics,
+            paused: Arc::new(AtomicBool::new(config.paused)),
+            leader: Arc::new(AtomicBool::new(leader)),
+            clock,
+            reconcile_requests: None,
+            readiness: Arc::new(Mutex::new(config.zones.iter()
+                .map(|zone| (zone.name.clone(), Readiness::default()))
+                .collect())),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(config.history_size))),
+            history_size: config.history_size,
+            webhook_zones: config.webhook.then(|| config.zones.clone()),
+            ready_max_failures: config.ready_max_failures,
+            ready_max_age: config.ready_max_age,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::Relaxed);
+        self.metrics.leader.set(if leader { 1 } else { 0 });
+    }
+
+    // Only the last `HISTORY_SIZE` reconciles of all zones are kept.
+    pub async fn record_history(&self, zone: &str, result: &Result<Vec<PlanAction>, String>) {
+        let mut history = self.history.lock().await;
+        history.push_back(HistoryEntry::new(zone, result, &*self.clock));
+        while history.len() > self.history_size {
+            history.pop_front();
+        }
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(value).unwrap()))
+        .unwrap()
+}
+
+// Ready while every zone is. A standby waiting for the leader election lease doesn't reconcile and is always ready.
+fn readyz(state: &ServerState, readiness: &BTreeMap<String, Readiness>) -> Response<Body> {
+    let mut response = json(readiness);
+    let ready = |zone: &Readiness| zone.ready(state.ready_max_failures, state.ready_max_age, &*state.clock);
+    if state.is_leader() && !readiness.values().all(ready) {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(code.canonical_reason().unwrap_or_default()))
+        .unwrap()
+}
+
+// The validating webhook for hostname annotations, Kubernetes only calls it through a TLS terminating proxy.
+async fn validate(req: Request<Body>, zones: &[ZoneConfig]) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return status(StatusCode::BAD_REQUEST)
+    };
+    match webhook::review(&body, zones) {
+        Ok(review) => json(&review),
+        Err(err) => {
+            warn!("Invalid admission review: {}", err);
+            status(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+// With `DEBUG_RECONCILE` the controller reconciles `?host=<hostname>` right away and responds with the planned
+// changes.
+async fn reconcile(req: &Request<Body>, requests: &Sender<ReconcileRequest>) -> Response<Body> {
+    let host = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find_map(|(name, value)| if name == "host" { Some(value.into_owned()) } else { None })
+        .filter(|host| !host.is_empty());
+    let host = match host {
+        Some(host) => host,
+        None => return status(StatusCode::BAD_REQUEST)
+    };
+
+    let (reply, response) = oneshot::channel();
+    if requests.send((host, reply)).await.is_err() {
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    match response.await {
+        Ok(Ok(changes)) => json(&changes),
+        Ok(Err(err)) => {
+            let mut response = json(&err);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+        Err(_) => status(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+async fn handle(req: Request<Body>, state: ServerState) -> Result<Response<Body>, Infallible> {
+    if let (&Method::POST, "/validate", Some(zones)) = (req.method(), req.uri().path(), &state.webhook_zones) {
+        return Ok(validate(req, zones).await);
+    }
+
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => status(StatusCode::OK),
+        (&Method::GET, "/readyz") => readyz(&state, &*state.readiness.lock().await),
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(state.metrics.encode()))
+            .unwrap(),
+        (&Method::GET, "/debug/state") => json(&*state.debug.lock().await),
+        (&Method::GET, "/debug/history") => json(&*state.history.lock().await),
+        (&Method::POST, "/debug/reconcile") => match &state.reconcile_requests {
+            Some(_) if !state.is_leader() => status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(requests) => reconcile(&req, requests).await,
+            None => status(StatusCode::NOT_FOUND)
+        },
+        _ => status(StatusCode::NOT_FOUND)
+    })
+}
+
+pub async fn serve(addr: SocketAddr, state: ServerState) {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone())))
+        }
+    });
+
+    match Server::try_bind(&addr) {
+        Ok(server) => if let Err(err) = server.serve(make_service).await {
+            error!("server error: {}", err);
+        },
+        Err(err) => error!("failed to bind server to {}: {}", addr, err)
+    }
+}
@@ -0,0 +1,59 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::{Metadata, NamespaceResourceScope, Resource};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IngressRoute {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub spec: IngressRouteSpec,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IngressRouteSpec {
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Route {
+    #[serde(rename = "match", default)]
+    pub match_: String,
+}
+
+impl Resource for IngressRoute {
+    const API_VERSION: &'static str = "traefik.io/v1alpha1";
+    const GROUP: &'static str = "traefik.io";
+    const KIND: &'static str = "IngressRoute";
+    const VERSION: &'static str = "v1alpha1";
+    const URL_PATH_SEGMENT: &'static str = "ingressroutes";
+    type Scope = NamespaceResourceScope;
+}
+
+impl Metadata for IngressRoute {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+impl IngressRoute {
+    /// Hostnames of all `Host(...)` matchers of the routes, e.g. ``Host(`a.example.com`) || Host(`b.example.com`)``.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts = Vec::new();
+        for route in &self.spec.routes {
+            for matcher in route.match_.split("Host(").skip(1) {
+                let args = matcher.split(')').next().unwrap_or_default();
+                hosts.extend(args.split(',')
+                    .map(|host| host.trim().trim_matches(|c| c == '`' || c == '"').to_string())
+                    .filter(|host| !host.is_empty()));
+            }
+        }
+        hosts
+    }
+}
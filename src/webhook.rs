@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::ZoneConfig;
+use crate::HOSTNAME_LABEL;
+use crate::plan::hostname_problem;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionReview {
+    api_version: String,
+    request: AdmissionRequest,
+}
+
+#[derive(Deserialize)]
+struct AdmissionRequest {
+    uid: String,
+    #[serde(default)]
+    object: Value,
+}
+
+/// Answers an `AdmissionReview` of a Service or Ingress, denying it when its hostname annotation isn't a valid
+/// hostname within one of the zones.
+pub fn review(body: &[u8], zones: &[ZoneConfig]) -> Result<Value, serde_json::Error> {
+    let review: AdmissionReview = serde_json::from_slice(body)?;
+    let problem = review.request.object.get("metadata")
+        .and_then(|metadata| metadata.get("annotations"))
+        .and_then(|annotations| annotations.get(HOSTNAME_LABEL))
+        .and_then(Value::as_str)
+        .and_then(|hostname| hostname_problem(hostname, zones));
+
+    let mut response = json!({
+        "uid": review.request.uid,
+        "allowed": problem.is_none(),
+    });
+    if let Some(problem) = problem {
+        response["status"] = json!({ "code": 403, "message": problem });
+    }
+    Ok(json!({
+        "apiVersion": review.api_version,
+        "kind": "AdmissionReview",
+        "response": response,
+    }))
+}